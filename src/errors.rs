@@ -1,7 +1,8 @@
 use std::fmt::{Display, Formatter};
 use std::error::Error;
+use bytes::Bytes;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KafkaCallerError(pub String);
 
 impl KafkaCallerError {
@@ -24,4 +25,23 @@ impl Error for KafkaCallerError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         None
     }
-}
\ No newline at end of file
+}
+
+// record which was fetched, but its key or value could not be deserialized, raw data are kept so application can handle it
+#[derive(Debug, Clone)]
+pub struct RecordDeserializationError {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Bytes>,
+    pub value: Option<Bytes>,
+    pub message: String,
+}
+
+impl Display for RecordDeserializationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not deserialize record at offset '{}' of partition '{}-{}': {}", self.offset, self.topic, self.partition, self.message)
+    }
+}
+
+impl Error for RecordDeserializationError {}
\ No newline at end of file
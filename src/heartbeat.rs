@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::TcpStream;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use kafka_protocol::messages::{ApiKey, HeartbeatRequest, HeartbeatResponse, LeaveGroupRequest, LeaveGroupResponse};
+use kafka_protocol::protocol::{Decodable, Encodable, Message, HeaderVersion};
+use crate::Configuration;
+use crate::io::IO;
+use crate::io::call_state::{CallState, GroupSubscription};
+use crate::io::messages::{CreateSerDe, SerDe, CreateRequest, ProcessResponse};
+
+// how membership ended while application was not polling, consumer joins the group again on next poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MembershipLoss {
+    // REBALANCE_IN_PROGRESS, ILLEGAL_GENERATION - member joins again with its member id
+    Rebalance,
+    // UNKNOWN_MEMBER_ID, or member left the group as max poll interval expired - member joins as a new one
+    Removed,
+}
+
+// membership shared by consumer and its heartbeat thread, condvar wakes the thread when membership changes or consumer is closed
+pub(crate) type SharedHeartbeat = Arc<(Mutex<HeartbeatState>, Condvar)>;
+
+#[derive(Debug)]
+pub(crate) struct HeartbeatState {
+    // membership heartbeats are sent for, None while consumer is not in the group
+    pub group_subscription: Option<GroupSubscription>,
+    pub last_poll: Instant,
+    // set by heartbeat thread, taken by the next poll
+    pub membership_loss: Option<MembershipLoss>,
+    pub closed: bool,
+}
+
+impl HeartbeatState {
+    pub fn new() -> Self {
+        Self {
+            group_subscription: None,
+            last_poll: Instant::now(),
+            membership_loss: None,
+            closed: false,
+        }
+    }
+}
+
+// Background part of the consumer group membership. It runs on its own thread with its own connection to the bootstrap broker
+// (which the consumer uses as group coordinator) and sends heartbeats within session timeout regardless of how often
+// application polls. When application does not poll within max poll interval, the member leaves the group, so its partitions
+// are assigned to other members, same as java client does.
+pub(crate) struct Heartbeat {
+    state: CallState,
+    io: Option<IO>,
+    shared: SharedHeartbeat,
+}
+
+impl Heartbeat {
+    // api versions are known only after consumer called ApiVersions, so the thread is started on first join
+    pub fn new(configuration: &Configuration, broker_api_versions: HashMap<i16, RangeInclusive<i16>>, shared: SharedHeartbeat) -> Result<Self, Box<dyn Error>> {
+        let mut state = CallState::new(configuration)?;
+        state.broker_api_versions = broker_api_versions;
+
+        Ok(
+            Self {
+                state,
+                io: None,
+                shared,
+            }
+        )
+    }
+
+    pub fn run(mut self) {
+        let max_poll_interval = Duration::from_millis(self.state.configuration.max_poll_interval_ms().unwrap_or(300000) as u64);
+        // third of session timeout (same as java client recommends), so the member survives a lost heartbeat
+        let heartbeat_interval = Duration::from_millis(self.state.configuration.session_timeout_ms().unwrap_or(45000) as u64 / 3);
+
+        loop {
+            let (group_subscription, poll_interval_expired) = {
+                let (lock, condvar) = &*self.shared;
+                let mut shared = condvar.wait_timeout(lock.lock().unwrap(), heartbeat_interval).unwrap().0;
+
+                if shared.closed {
+                    break;
+                }
+
+                match shared.group_subscription.clone() {
+                    None => continue,
+                    // membership is given up before LeaveGroup is sent, so poll which comes meanwhile joins again
+                    Some(group_subscription) if shared.last_poll.elapsed() > max_poll_interval => {
+                        shared.group_subscription = None;
+                        shared.membership_loss = Some(MembershipLoss::Removed);
+
+                        (group_subscription, true)
+                    },
+                    Some(group_subscription) => (group_subscription, false),
+                }
+            };
+
+            self.state.group_subscription = group_subscription;
+
+            if poll_interval_expired {
+                // when LeaveGroup fails, coordinator removes the member after session timeout
+                let _ = self.do_call::<LeaveGroupRequest, LeaveGroupResponse>(ApiKey::LeaveGroupKey);
+            } else {
+                self.send_heartbeat();
+            }
+        }
+    }
+
+    // failed heartbeat is sent again after heartbeat interval, coordinator removes the member only after session timeout
+    fn send_heartbeat(&mut self) {
+        let generation_id = self.state.group_subscription.generation_id;
+
+        if self.do_call::<HeartbeatRequest, HeartbeatResponse>(ApiKey::HeartbeatKey).is_err() {
+            return;
+        }
+
+        // coordinator ended the membership, heartbeat response reset it the same way as when heartbeat was sent from poll
+        if self.state.group_subscription.generation_id == -1 {
+            let membership_loss = 
+                if self.state.group_subscription.member_id.is_empty() {
+                    MembershipLoss::Removed
+                } else {
+                    MembershipLoss::Rebalance
+                };
+
+            let (lock, _) = &*self.shared;
+            let mut shared = lock.lock().unwrap();
+
+            // consumer may have joined again with new generation meanwhile
+            if shared.group_subscription.as_ref().map(|group_subscription| group_subscription.generation_id) == Some(generation_id) {
+                shared.group_subscription = None;
+                shared.membership_loss = Some(membership_loss);
+            }
+        }
+    }
+
+    // connection is opened lazily and dropped when call fails, so it is opened again for the next one
+    fn do_call<Req, Res>(&mut self, api_key: ApiKey) -> Result<(), Box<dyn Error>>
+        where
+            Req: Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        let ser_de: SerDe<Req, Res> = api_key.new_ser_de(Some(&self.state))?;
+
+        let request_bytes = 
+            ser_de.serialize(
+                &self.state.configuration.client_id(), 
+                self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),  
+                Req::default().create_request(&self.state)?
+            )?;
+
+        let call_result = 
+            self.io()
+                .and_then(|io| io.call(request_bytes));
+
+        let mut response_bytes = 
+            match call_result {
+                Ok(response_bytes) => response_bytes,
+                Err(error) => {
+                    self.io = None;
+                    return Err(error);
+                },
+            };
+
+        let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
+
+        response_body.process_response(&mut self.state)
+    }
+
+    // response not arriving within session timeout would come too late anyway
+    fn io(&mut self) -> Result<&mut IO, Box<dyn Error>> {
+        if self.io.is_none() {
+            let io = IO::from(TcpStream::connect(self.state.configuration.broker_address())?);
+            io.set_timeout(Duration::from_millis(self.state.configuration.session_timeout_ms()? as u64))?;
+            self.io = Some(io);
+        }
+
+        Ok(self.io.as_mut().expect("Connection was just opened"))
+    }
+}
@@ -0,0 +1,25 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::errors::KafkaCallerError;
+use crate::io::records::{ConsumerRecords, PutRecord, RecordMetadata, TopicPartition};
+
+// Hooks of producer, same as ProducerInterceptor of java client. on_send is called on the calling thread before the record
+// is serialized and partitioned, on_acknowledgement on the sender thread, which is why interceptor is shared and takes &self.
+pub trait ProducerInterceptor<K = Bytes, V = Bytes>: Send + Sync {
+    // may modify the record, e.g. add tracing headers
+    fn on_send(&self, record: &mut PutRecord<K, V>);
+
+    // record was delivered or failed, called before result is passed to its SendHandle
+    fn on_acknowledgement(&self, _result: Result<&RecordMetadata, &KafkaCallerError>) {}
+}
+
+// Hooks of consumer, same as ConsumerInterceptor of java client, called on the thread polling the consumer
+pub trait ConsumerInterceptor<K = Bytes, V = Bytes> {
+    // called with deserialized records before poll returns them, records returned by interceptor are passed on
+    fn on_consume(&mut self, records: ConsumerRecords<K, V>) -> ConsumerRecords<K, V>;
+
+    // offsets (of the next record to consume) were committed for the group
+    fn on_commit(&mut self, _offsets: &HashMap<TopicPartition, i64>) {}
+}
@@ -1,11 +1,14 @@
 use std::error::Error;
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 use bytes::{Bytes, BytesMut};
 
 pub(super) mod messages;
 pub(super) mod records;
+pub(super) mod record_batch;
 pub(super) mod call_state;
+pub(super) mod accumulator;
 
 pub(super) struct IO {
     tcp_stream: TcpStream
@@ -21,8 +24,7 @@ impl From<TcpStream> for IO {
 
 impl IO {
     pub fn call(&mut self, request: BytesMut) -> Result<Bytes, Box<dyn Error>> {
-        self.tcp_stream.write_all(&request)?;
-        self.tcp_stream.flush()?;
+        self.send(request)?;
 
         let response_length = {
             let mut response_length_bytes: [u8; 4] = [0; 4];
@@ -37,6 +39,19 @@ impl IO {
         Ok(Bytes::from(response))
     }
 
+    // request is only written, for requests broker does not answer (produce with acks=0)
+    pub fn send(&mut self, request: BytesMut) -> Result<(), Box<dyn Error>> {
+        self.tcp_stream.write_all(&request)?;
+        self.tcp_stream.flush()?;
+
+        Ok(())
+    }
+
+    // reading response fails when broker does not answer within timeout
+    pub fn set_timeout(&self, timeout: Duration) -> Result<(), std::io::Error> {
+        self.tcp_stream.set_read_timeout(Some(timeout))
+    }
+
     pub fn try_clone(&self) -> Result<Self, std::io::Error> {
         let result = 
             Self {
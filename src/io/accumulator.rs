@@ -0,0 +1,473 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant, SystemTime};
+use indexmap::IndexMap;
+use crate::ConsumerGroupMetadata;
+use crate::errors::KafkaCallerError;
+
+use super::call_state::BrokerMetadata;
+use super::records::{PutRecord, RecordMetadata, TimestampType, TopicPartition};
+
+type DeliveryResult = Result<RecordMetadata, KafkaCallerError>;
+
+// called by sender thread with result of every record before it is passed to its handle, used for producer interceptors
+#[derive(Clone)]
+pub(crate) struct AcknowledgementHook(pub Arc<dyn Fn(&DeliveryResult) + Send + Sync>);
+
+impl Debug for AcknowledgementHook {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
+        formatter.write_str("AcknowledgementHook")
+    }
+}
+
+// same as "retry.backoff.ms" default of java client
+pub(crate) const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+// header of record batch (magic v2)
+pub(crate) const RECORD_BATCH_OVERHEAD: usize = 61;
+// length, attributes, timestamp delta and offset delta of one record, varints at their largest (same as java client),
+// lengths of key, value and headers are part of the record size estimate
+pub(crate) const MAX_RECORD_OVERHEAD: usize = 21;
+
+// transactional operations are performed by sender thread, which owns connections to transaction coordinator
+#[derive(Debug)]
+pub(crate) enum TransactionCommand {
+    Init,
+    // performed once all records sent before it were delivered
+    Commit,
+    // records which were not sent yet are failed
+    Abort,
+    SendOffsets {
+        offsets: HashMap<TopicPartition, i64>,
+        group_metadata: ConsumerGroupMetadata,
+    },
+}
+
+pub(crate) type TransactionResult = Result<(), KafkaCallerError>;
+
+// accumulator is shared by producer (appending records) and its sender thread (draining batches),
+// condvar is notified on every change either side may wait for
+pub(crate) type SharedAccumulator = Arc<(Mutex<RecordAccumulator>, Condvar)>;
+
+// returned by Producer::send, resolved by the sender thread once the batch with the record is acknowledged or fails
+#[derive(Debug)]
+pub struct SendHandle {
+    receiver: Receiver<DeliveryResult>,
+}
+
+impl SendHandle {
+    // blocks until the record is delivered
+    pub fn wait(self) -> Result<RecordMetadata, KafkaCallerError> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(KafkaCallerError::new("Producer was closed before the record was delivered")))
+    }
+
+    // None while the record is still waiting to be delivered, result can be taken only once
+    pub fn try_result(&self) -> Option<Result<RecordMetadata, KafkaCallerError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(KafkaCallerError::new("Producer was closed before the record was delivered"))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ProducerBatch {
+    pub topic_partition: TopicPartition,
+    pub records: Vec<PutRecord>,
+    // create time of each record, given by the record or taken when it was appended
+    pub timestamps: Vec<i64>,
+    result_senders: Vec<mpsc::Sender<DeliveryResult>>,
+    acknowledgement_hook: Option<AcknowledgementHook>,
+    pub size_bytes: usize,
+    created: Instant,
+    // sequence of the first record for idempotent producer, assigned when batch is sent first time and kept for retries
+    pub base_sequence: i32,
+    // set when batch failed with retriable error and was put back to accumulator
+    retry_after: Option<Instant>,
+    last_error: Option<KafkaCallerError>,
+}
+
+impl ProducerBatch {
+    fn new(topic_partition: TopicPartition, acknowledgement_hook: Option<AcknowledgementHook>) -> Self {
+        Self {
+            topic_partition,
+            records: Vec::new(),
+            timestamps: Vec::new(),
+            result_senders: Vec::new(),
+            acknowledgement_hook,
+            size_bytes: 0,
+            created: Instant::now(),
+            base_sequence: -1,
+            retry_after: None,
+            last_error: None,
+        }
+    }
+
+    fn push(&mut self, record: PutRecord, record_size: usize) -> SendHandle {
+        let (sender, receiver) = mpsc::channel();
+
+        let timestamp = 
+            record.timestamp.unwrap_or_else(||
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|duration| duration.as_millis() as i64)
+                    .unwrap_or(-1)
+            );
+
+        self.records.push(record);
+        self.timestamps.push(timestamp);
+        self.result_senders.push(sender);
+        self.size_bytes += record_size;
+
+        SendHandle {
+            receiver,
+        }
+    }
+
+    // batch was appended at base offset, log append time is -1 unless the topic uses LogAppendTime.
+    // handles which were dropped are ignored
+    pub fn complete(self, base_offset: i64, log_append_time: i64) {
+        let topic_partition = self.topic_partition;
+
+        for ((index, result_sender), timestamp) in self.result_senders.into_iter().enumerate().zip(self.timestamps) {
+            let (timestamp, timestamp_type) = 
+                if log_append_time != -1 {
+                    (log_append_time, TimestampType::LogAppendTime)
+                } else {
+                    (timestamp, TimestampType::CreateTime)
+                };
+
+            let record_metadata =
+                RecordMetadata {
+                    topic: topic_partition.topic.clone(),
+                    partition: topic_partition.partition,
+                    offset: base_offset + index as i64,
+                    timestamp,
+                    timestamp_type,
+                };
+
+            deliver(&self.acknowledgement_hook, result_sender, Ok(record_metadata));
+        }
+    }
+
+    // offsets are unknown with acks=0 when broker does not answer, or for duplicate batch written before
+    pub fn complete_without_offset(self) {
+        for (result_sender, timestamp) in self.result_senders.into_iter().zip(self.timestamps) {
+            let record_metadata =
+                RecordMetadata {
+                    topic: self.topic_partition.topic.clone(),
+                    partition: self.topic_partition.partition,
+                    offset: -1,
+                    timestamp,
+                    timestamp_type: TimestampType::CreateTime,
+                };
+
+            deliver(&self.acknowledgement_hook, result_sender, Ok(record_metadata));
+        }
+    }
+
+    // halves of the batch for the same partition, keeping its create time so delivery timeout is not extended
+    fn split(mut self) -> (ProducerBatch, ProducerBatch) {
+        let split_at = self.records.len() / 2;
+
+        let mut second = ProducerBatch::new(self.topic_partition.clone(), self.acknowledgement_hook.clone());
+        second.created = self.created;
+        second.records = self.records.split_off(split_at);
+        second.timestamps = self.timestamps.split_off(split_at);
+        second.result_senders = self.result_senders.split_off(split_at);
+        second.size_bytes = second.records.iter().map(|record| record.size_in_bytes()).sum();
+        second.last_error = self.last_error.clone();
+
+        self.size_bytes -= second.size_bytes;
+        self.base_sequence = -1;
+        self.retry_after = None;
+
+        (self, second)
+    }
+
+    fn is_ready_for_retry(&self, now: Instant) -> bool {
+        self.retry_after.map(|retry_after| retry_after <= now).unwrap_or(true)
+    }
+
+    // failed with last error when it was retried, with timeout otherwise
+    fn fail_expired(self, delivery_timeout: Duration) -> KafkaCallerError {
+        let error = 
+            match &self.last_error {
+                Some(last_error) => KafkaCallerError::new(&format!(
+                    "Delivery timeout of {} ms expired for partition {} of topic '{}', last error: {}", 
+                    delivery_timeout.as_millis(), self.topic_partition.partition, self.topic_partition.topic, last_error.0
+                )),
+                None => KafkaCallerError::new(&format!(
+                    "Delivery timeout of {} ms expired for partition {} of topic '{}'", 
+                    delivery_timeout.as_millis(), self.topic_partition.partition, self.topic_partition.topic
+                )),
+            };
+
+        self.fail(error.clone());
+        error
+    }
+
+    pub fn fail(self, error: KafkaCallerError) {
+        for result_sender in self.result_senders {
+            deliver(&self.acknowledgement_hook, result_sender, Err(error.clone()));
+        }
+    }
+
+    // records reported by broker (by index in the batch) fail with their own message, the rest with the batch error
+    pub fn fail_with_record_errors(self, error: KafkaCallerError, record_errors: &HashMap<usize, String>) {
+        for (index, result_sender) in self.result_senders.into_iter().enumerate() {
+            let record_error = 
+                match record_errors.get(&index) {
+                    Some(message) => KafkaCallerError::new(message),
+                    None => error.clone(),
+                };
+
+            deliver(&self.acknowledgement_hook, result_sender, Err(record_error));
+        }
+    }
+}
+
+// records waiting to be sent, collected into batches per topic partition (same as RecordAccumulator of java client)
+#[derive(Debug)]
+pub(crate) struct RecordAccumulator {
+    batch_size: usize,
+    linger: Duration,
+    buffer_memory: usize,
+    delivery_timeout: Duration,
+    batches: IndexMap<TopicPartition, VecDeque<ProducerBatch>>,
+    buffered_bytes: usize,
+    // topics which producer waits metadata for before their records can be partitioned
+    pub pending_topics: HashSet<String>,
+    // copy of sender thread metadata, used for partitioning
+    pub broker_metadata: BrokerMetadata,
+    // set when sender thread stopped, nothing can be sent anymore
+    pub sender_error: Option<KafkaCallerError>,
+    // set when producer is closed, all batches are sent regardless of linger
+    pub closed: bool,
+    // batches are sent regardless of linger until accumulator is empty
+    pub flush_requested: bool,
+    // set when close timed out, batches put back by sender thread are failed instead of sent again
+    pub force_closed: bool,
+    // batches drained by sender thread which are not completed or put back yet
+    pub in_flight_batches: usize,
+    pub transaction_commands: VecDeque<(TransactionCommand, mpsc::Sender<TransactionResult>)>,
+    // passed to batches created from now on
+    pub acknowledgement_hook: Option<AcknowledgementHook>,
+    // batch which already had a sequence expired, sequence of its partition moved past it, so sender has to bump producer epoch
+    pub sequence_lost: bool,
+}
+
+impl RecordAccumulator {
+    pub fn new(batch_size: usize, linger_ms: u64, buffer_memory: usize, delivery_timeout_ms: u64) -> Self {
+        Self {
+            batch_size,
+            linger: Duration::from_millis(linger_ms),
+            buffer_memory,
+            delivery_timeout: Duration::from_millis(delivery_timeout_ms),
+            batches: IndexMap::new(),
+            buffered_bytes: 0,
+            pending_topics: HashSet::new(),
+            broker_metadata: BrokerMetadata::default(),
+            sender_error: None,
+            closed: false,
+            flush_requested: false,
+            force_closed: false,
+            in_flight_batches: 0,
+            transaction_commands: VecDeque::new(),
+            acknowledgement_hook: None,
+            sequence_lost: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.values().all(|batches| batches.is_empty())
+    }
+
+    // nothing waits to be sent and nothing waits for broker response
+    pub fn is_done(&self) -> bool {
+        self.in_flight_batches == 0 && self.is_empty()
+    }
+
+    // record larger than the whole buffer is accepted when buffer is empty, so it does not wait forever
+    pub fn has_room(&self, record_size: usize) -> bool {
+        self.buffered_bytes == 0 || self.buffered_bytes + record_size <= self.buffer_memory
+    }
+
+    // record has to have partition assigned already
+    pub fn append(&mut self, record: PutRecord) -> SendHandle {
+        let topic_partition = TopicPartition::new(&record.topic, record.partition.unwrap_or(-1));
+        let record_size = record.size_in_bytes();
+        let batch_size = self.batch_size;
+
+        let batches = self.batches.entry(topic_partition.clone()).or_default();
+
+        let has_space =
+            batches
+                .back()
+                .map(|batch| batch.records.is_empty() || batch.size_bytes + record_size <= batch_size)
+                .unwrap_or(false);
+
+        if !has_space {
+            batches.push_back(ProducerBatch::new(topic_partition, self.acknowledgement_hook.clone()));
+        }
+
+        self.buffered_bytes += record_size;
+
+        batches
+            .back_mut()
+            .expect("Batch was just created")
+            .push(record, record_size)
+    }
+
+    // takes first batch of every partition which is full, lingered long enough, or all of them when producer is closing
+    pub fn drain_ready(&mut self, now: Instant) -> Vec<ProducerBatch> {
+        let mut ready_batches = Vec::new();
+
+        for batches in self.batches.values_mut() {
+            let is_ready =
+                batches
+                    .front()
+                    .map(|batch|
+                        batch.is_ready_for_retry(now) && (
+                            self.closed ||
+                            self.flush_requested ||
+                            batches.len() > 1 ||
+                            batch.size_bytes >= self.batch_size ||
+                            now.duration_since(batch.created) >= self.linger
+                        )
+                    )
+                    .unwrap_or(false);
+
+            if is_ready {
+                if let Some(batch) = batches.pop_front() {
+                    self.buffered_bytes -= batch.size_bytes;
+                    ready_batches.push(batch);
+                }
+            }
+        }
+
+        ready_batches
+    }
+
+    // time until the oldest batch lingers long enough, or its retry backoff passes
+    pub fn next_ready_in(&self, now: Instant) -> Option<Duration> {
+        self.batches
+            .values()
+            .filter_map(|batches| batches.front())
+            .map(|batch| 
+                batch.retry_after
+                    .unwrap_or(batch.created + self.linger)
+                    .saturating_duration_since(now)
+            )
+            .min()
+    }
+
+    // batch failed with retriable error, it is sent again before newer batches of the partition after backoff,
+    // unless delivery timeout expired already, then it is failed and the error is returned
+    pub fn reenqueue(&mut self, mut batch: ProducerBatch, error: KafkaCallerError, now: Instant) -> Option<KafkaCallerError> {
+        batch.last_error = Some(error);
+
+        if now.duration_since(batch.created) >= self.delivery_timeout {
+            self.sequence_lost |= batch.base_sequence != -1;
+            return Some(batch.fail_expired(self.delivery_timeout));
+        }
+
+        batch.retry_after = Some(now + RETRY_BACKOFF);
+
+        self.buffered_bytes += batch.size_bytes;
+        self.batches
+            .entry(batch.topic_partition.clone())
+            .or_default()
+            .push_front(batch);
+
+        None
+    }
+
+    // batch was rejected by broker as too large, its halves are sent before newer batches of the partition without backoff
+    pub fn split_and_reenqueue(&mut self, batch: ProducerBatch) {
+        let (first, second) = batch.split();
+
+        self.buffered_bytes += first.size_bytes + second.size_bytes;
+
+        let batches = self.batches.entry(first.topic_partition.clone()).or_default();
+        batches.push_front(second);
+        batches.push_front(first);
+    }
+
+    // producer epoch was bumped, batches waiting for retry get new sequences when sent again
+    pub fn reset_sequences(&mut self) {
+        for batch in self.batches.values_mut().flat_map(|batches| batches.iter_mut()) {
+            batch.base_sequence = -1;
+        }
+    }
+
+    // fails batches waiting longer than delivery timeout, returns error of the last one failed
+    pub fn fail_expired(&mut self, now: Instant) -> Option<KafkaCallerError> {
+        let delivery_timeout = self.delivery_timeout;
+        let mut expired_error = None;
+
+        for batches in self.batches.values_mut() {
+            while batches.front().map(|batch| now.duration_since(batch.created) >= delivery_timeout).unwrap_or(false) {
+                if let Some(batch) = batches.pop_front() {
+                    self.buffered_bytes -= batch.size_bytes;
+                    self.sequence_lost |= batch.base_sequence != -1;
+                    expired_error = Some(batch.fail_expired(delivery_timeout));
+                }
+            }
+        }
+
+        expired_error
+    }
+
+    // records which were not sent yet are failed, used when transaction is aborted or producer closed, returns their count
+    pub fn abort_batches(&mut self, error: KafkaCallerError) -> usize {
+        let mut aborted_records = 0;
+
+        for (_, batches) in self.batches.drain(..) {
+            for batch in batches {
+                aborted_records += batch.records.len();
+                self.sequence_lost |= batch.base_sequence != -1;
+                batch.fail(error.clone());
+            }
+        }
+
+        self.buffered_bytes = 0;
+
+        aborted_records
+    }
+
+    // sender thread stopped, records and transaction commands still waiting are failed with its error
+    pub fn fail_all(&mut self, error: KafkaCallerError) {
+        self.abort_batches(error.clone());
+
+        for (_, result_sender) in self.transaction_commands.drain(..) {
+            let _ = result_sender.send(Err(error.clone()));
+        }
+
+        self.pending_topics.clear();
+        self.sender_error = Some(error);
+    }
+}
+
+// handles which were dropped are ignored, hook is called regardless
+fn deliver(acknowledgement_hook: &Option<AcknowledgementHook>, result_sender: mpsc::Sender<DeliveryResult>, result: DeliveryResult) {
+    if let Some(AcknowledgementHook(hook)) = acknowledgement_hook {
+        hook(&result);
+    }
+
+    let _ = result_sender.send(result);
+}
+
+// sequence numbers wrap to 0 after i32::MAX, same as java client
+pub(crate) fn increment_sequence(sequence: i32, increment: i32) -> i32 {
+    if sequence > i32::MAX - increment {
+        increment - (i32::MAX - sequence) - 1
+    } else {
+        sequence + increment
+    }
+}
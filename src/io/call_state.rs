@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ops::RangeInclusive;
 use std::sync::{Arc, atomic::AtomicI32};
 use bytes::Bytes;
 use uuid::Uuid;
-use crate::Configuration;
+use crate::{Configuration, ConsumerGroupMetadata};
 use crate::errors::KafkaCallerError;
 
-use super::records::PutRecord;
+use super::accumulator::ProducerBatch;
+use super::records::TopicPartition;
+
+// key types of FindCoordinator, also part of the key of stored coordinators
+pub(crate) const GROUP_COORDINATOR: i8 = 0;
+pub(crate) const TRANSACTION_COORDINATOR: i8 = 1;
 
 #[derive(Debug)]
 pub(in super::super) struct CallState {
@@ -16,11 +21,43 @@ pub(in super::super) struct CallState {
     pub broker_api_versions: HashMap<i16, RangeInclusive<i16>>,
     pub connected_topics: Vec<String>,
     pub broker_metadata: BrokerMetadata,
-    pub coordinators: HashMap<String, Coordinator>,
+    // keyed by key type and key, as transactional id and group id may be the same string
+    pub coordinators: HashMap<(i8, String), Coordinator>,
     pub group_subscription: GroupSubscription,
     pub fetch_state: HashMap<String, HashMap<i32, PartitionOffsetState>>,
+    // fetch sessions are held by each broker separately
+    pub fetch_sessions: HashMap<i32, FetchSession>,
+    // broker which the next broker specific request (Fetch, OffsetForLeaderEpoch) is sent to
+    pub target_node_id: i32,
+    // set when fetch finds out that leaders of some partitions changed
+    pub metadata_refresh_needed: bool,
+    // partition error from fetch which returned records for other partitions, it is raised on the next poll
+    pub pending_fetch_error: Option<KafkaCallerError>,
     pub producer_id: i64,
-    pub records_to_send: Vec<PutRecord>,
+    pub producer_epoch: i16,
+    // next sequence number of each partition for idempotent producer
+    pub producer_sequences: HashMap<(String, i32), i32>,
+    // broker lost producer state or sequences got out of order, epoch is bumped and sequences start over (KIP-360)
+    pub producer_epoch_bump_needed: bool,
+    // partitions added to the current transaction by AddPartitionsToTxn
+    pub transaction_partitions: HashSet<(String, i32)>,
+    // whether the next EndTxn commits or aborts the transaction
+    pub transaction_committed: bool,
+    // records of the current transaction failed, so it cannot be committed
+    pub transaction_error: Option<KafkaCallerError>,
+    // consumer offsets sent by the next AddOffsetsToTxn and TxnOffsetCommit
+    pub transaction_offsets: HashMap<TopicPartition, i64>,
+    pub transaction_group_metadata: Option<ConsumerGroupMetadata>,
+    // transaction has offsets added, so it is known to coordinator even without partitions
+    pub transaction_offsets_added: bool,
+    // coordinator looked up by the next FindCoordinator when it differs from the one given by configuration, with its key type
+    pub coordinator_key: Option<(String, i8)>,
+    // batches drained from accumulator for the target node, sent by the next Produce request
+    pub batches_to_send: Vec<ProducerBatch>,
+    // batches which failed with retriable error, with the error they failed with
+    pub batches_to_retry: Vec<(ProducerBatch, KafkaCallerError)>,
+    // batches rejected by broker as too large, sent again split in halves
+    pub batches_to_split: Vec<ProducerBatch>,
 }
 
 impl CallState {
@@ -35,11 +72,131 @@ impl CallState {
                 coordinators: HashMap::new(),
                 group_subscription: GroupSubscription::default(),
                 fetch_state: HashMap::new(),
+                fetch_sessions: HashMap::new(),
+                target_node_id: -1,
+                metadata_refresh_needed: false,
+                pending_fetch_error: None,
                 producer_id: -1,
-                records_to_send: Vec::new(),
+                producer_epoch: -1,
+                producer_sequences: HashMap::new(),
+                producer_epoch_bump_needed: false,
+                transaction_partitions: HashSet::new(),
+                transaction_committed: false,
+                transaction_error: None,
+                transaction_offsets: HashMap::new(),
+                transaction_group_metadata: None,
+                transaction_offsets_added: false,
+                coordinator_key: None,
+                batches_to_send: Vec::new(),
+                batches_to_retry: Vec::new(),
+                batches_to_split: Vec::new(),
             }
         )
     }
+
+    // broker the partition is fetched from - preferred read replica returned by the leader (KIP-392), or the leader itself
+    pub(super) fn partition_fetch_node(&self, topic_name: &str, index: i32, offset_state: &PartitionOffsetState) -> Option<i32> {
+        if offset_state.preferred_read_replica != -1 && self.broker_metadata.brokers.contains_key(&offset_state.preferred_read_replica) {
+            return Some(offset_state.preferred_read_replica);
+        }
+
+        self.broker_metadata
+            .partition(topic_name, index)
+            .map(|partition| partition.leader_id)
+            .filter(|leader_id| self.broker_metadata.brokers.contains_key(leader_id))
+    }
+
+    pub fn offset_listing_needed(&self) -> bool {
+        self.fetch_state
+            .values()
+            .flat_map(|partitions| partitions.values())
+            .any(|offset_state| offset_state.awaits_offset_listing())
+    }
+
+    pub fn position_validation_needed(&self) -> bool {
+        self.fetch_state
+            .values()
+            .flat_map(|partitions| partitions.values())
+            .any(|offset_state| offset_state.position_validation_needed)
+    }
+
+    // leaders of partitions whose position has to be validated by OffsetForLeaderEpoch
+    pub fn position_validation_node_ids(&self) -> HashSet<i32> {
+        self.fetch_state
+            .iter()
+            .flat_map(|(name, partitions)|
+                partitions
+                    .iter()
+                    .filter(|(_, offset_state)| offset_state.position_validation_needed)
+                    .filter_map(move |(index, _)| self.broker_metadata.partition(name, *index).map(|partition| partition.leader_id))
+            )
+            .collect()
+    }
+
+    pub fn has_uncommitted_offsets(&self) -> bool {
+        self.fetch_state
+            .values()
+            .flat_map(|partitions| partitions.values())
+            .any(|offset_state| offset_state.has_uncommitted_offset())
+    }
+
+    // offsets (of the next record to consume) committed by next OffsetCommit
+    pub fn uncommitted_offsets(&self) -> HashMap<TopicPartition, i64> {
+        self.fetch_state
+            .iter()
+            .flat_map(|(topic, partitions)| 
+                partitions
+                    .iter()
+                    .filter(|(_, offset_state)| offset_state.has_uncommitted_offset())
+                    .map(move |(index, offset_state)| (TopicPartition::new(topic, *index), offset_state.consumed_offset + 1))
+            )
+            .collect()
+    }
+
+    // leaders of all fetched partitions, offsets are listed by the leader of each partition
+    pub fn partition_leader_ids(&self) -> HashSet<i32> {
+        self.fetch_state
+            .iter()
+            .flat_map(|(name, partitions)|
+                partitions
+                    .keys()
+                    .filter_map(move |index| self.broker_metadata.partition(name, *index).map(|partition| partition.leader_id))
+            )
+            .filter(|leader_id| *leader_id >= 0)
+            .collect()
+    }
+
+    // all brokers which have at least one partition to fetch from
+    pub fn fetch_node_ids(&self) -> HashSet<i32> {
+        self.fetch_state
+            .iter()
+            .flat_map(|(name, partitions)|
+                partitions
+                    .iter()
+                    .filter(|(_, offset_state)| !offset_state.awaits_offset_listing())
+                    .filter_map(move |(index, offset_state)| self.partition_fetch_node(name, *index, offset_state))
+            )
+            .collect()
+    }
+
+    // transaction is known to coordinator once it has some partitions or offsets added
+    pub fn in_transaction(&self) -> bool {
+        !self.transaction_partitions.is_empty() || self.transaction_offsets_added
+    }
+
+    // Epoch is never bumped while transaction is in progress, as coordinator would abort the transaction on its own
+    // and its commit would then look successful. Transaction which lost a sequence is aborted first.
+    pub fn producer_epoch_bump_allowed(&self) -> bool {
+        self.producer_epoch_bump_needed && !self.in_transaction()
+    }
+
+    // transaction whose records or offsets failed cannot be committed, only aborted
+    pub fn check_transaction_committable(&self) -> Result<(), KafkaCallerError> {
+        match &self.transaction_error {
+            Some(error) => Err(KafkaCallerError::new(&format!("Transaction cannot be committed as some of its records failed, it has to be aborted: {}", error.0))),
+            None => Ok(()),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -71,6 +228,20 @@ impl BrokerMetadata {
             })
             .ok_or(KafkaCallerError::new(&format!("Could not find topic name for id: '{}'", topic_id)))
     }
+
+    pub(crate) fn partition(&self, topic_name: &str, index: i32) -> Option<&Partition> {
+        self.topics
+            .get(topic_name)?
+            .partitions
+            .get(&index)
+    }
+
+    // -1 when leader epoch is not known
+    pub(super) fn leader_epoch(&self, topic_name: &str, index: i32) -> i32 {
+        self.partition(topic_name, index)
+            .map(|partition| partition.leader_epoch)
+            .unwrap_or(-1)
+    }
 }
 
 #[allow(dead_code)]
@@ -79,6 +250,7 @@ pub(crate) struct Broker {
     pub id: i32,
     pub host: String,
     pub port: i32,
+    pub rack: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -93,6 +265,9 @@ pub(crate) struct Topic {
 pub(crate) struct Partition {
     pub index: i32,
     pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub replica_ids: Vec<i32>,
+    pub isr_ids: Vec<i32>,
 }
 
 #[allow(dead_code)]
@@ -132,16 +307,107 @@ pub(crate) struct SubscriptionMember {
 #[derive(Debug, Clone)]
 pub(crate) struct PartitionOffsetState {
     pub commited_offset: i64,
+    // last fetched record
     pub polled_offset: i64,
-    pub error_code: i16,
+    // leader epoch of last fetched record, -1 if not known
+    pub last_fetched_epoch: i32,
+    // last record returned to application
+    pub consumed_offset: i64,
+    // leader epoch of last record returned to application, -1 if not known
+    pub consumed_leader_epoch: i32,
+    // set when partition leader changed, position is then checked by OffsetForLeaderEpoch for log truncation before next fetch
+    pub position_validation_needed: bool,
+    // set after OFFSET_OUT_OF_RANGE, partition is not fetched until ListOffsets resets its position
+    pub offset_reset_needed: bool,
+    // set when ListOffsets failed because metadata was stale, partition is not fetched until offsets are listed again
+    pub offset_listing_needed: bool,
+    // follower the leader asked to fetch this partition from, -1 if partition is fetched from leader
+    pub preferred_read_replica: i32,
 }
 
 impl PartitionOffsetState {
-    pub(super) fn new(index: i64) -> Self {
+    pub(crate) fn new(index: i64) -> Self {
         Self {
             commited_offset: index,
             polled_offset: -1,
-            error_code: 0,
+            last_fetched_epoch: -1,
+            consumed_offset: -1,
+            consumed_leader_epoch: -1,
+            position_validation_needed: false,
+            offset_reset_needed: false,
+            offset_listing_needed: false,
+            preferred_read_replica: -1,
+        }
+    }
+
+    // position of partition is not known until ListOffsets answers for it
+    pub(crate) fn awaits_offset_listing(&self) -> bool {
+        self.offset_reset_needed || self.offset_listing_needed
+    }
+
+    pub(super) fn has_uncommitted_offset(&self) -> bool {
+        self.consumed_offset >= 0 && self.consumed_offset + 1 != self.commited_offset
+    }
+
+    // Log of the partition was truncated (e.g. after unclean leader election) and records from end offset on were lost,
+    // so position moves back to end offset of the last epoch both client and broker know about
+    pub(crate) fn truncate(&mut self, end_offset: i64, epoch: i32) {
+        self.polled_offset = end_offset - 1;
+        self.last_fetched_epoch = epoch;
+
+        if self.consumed_offset >= end_offset {
+            self.consumed_offset = end_offset - 1;
+            self.consumed_leader_epoch = epoch;
+        }
+    }
+
+    // offset the next fetch starts from, continues after last polled record, if there is none then from committed offset
+    pub(crate) fn fetch_offset(&self) -> i64 {
+        if self.polled_offset >= 0 {
+            self.polled_offset + 1
+        } else if self.commited_offset == -1 {
+            0
+        } else {
+            self.commited_offset
+        }
+    }
+}
+
+// incremental fetch session (KIP-227) with the broker. Id 0 and epoch 0 means full fetch which asks broker to create new session.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FetchSession {
+    pub id: i32,
+    pub epoch: i32,
+    // partitions with fetch offsets which the broker holds for this session
+    pub partitions: HashMap<(String, i32), i64>,
+}
+
+impl FetchSession {
+    pub(crate) fn is_incremental(&self) -> bool {
+        self.id != 0
+    }
+
+    pub(crate) fn update(&mut self, response_session_id: i32, sent_partitions: HashMap<(String, i32), i64>) {
+        if response_session_id == 0 {
+            // broker did not create session (e.g. its session cache is full), next fetch is a full one again
+            self.reset();
+            return;
         }
+
+        self.epoch =
+            if self.id != response_session_id {
+                1
+            } else if self.epoch == i32::MAX {
+                // epoch 0 is reserved for session creation
+                1
+            } else {
+                self.epoch + 1
+            };
+        self.id = response_session_id;
+        self.partitions = sent_partitions;
+    }
+
+    pub(super) fn reset(&mut self) {
+        *self = Self::default();
     }
 }
\ No newline at end of file
@@ -11,6 +11,11 @@ mod leave_group;
 mod heartbeat;
 mod init_producer_id;
 mod produce;
+mod offset_for_leader_epoch;
+mod add_partitions_to_txn;
+mod end_txn;
+mod add_offsets_to_txn;
+mod txn_offset_commit;
 
 use std::cmp::min;
 use std::error::Error;
@@ -44,11 +49,17 @@ impl<Req, Res> CreateSerDe<Req, Res> for ApiKey
             ApiKey::HeartbeatKey | 
             ApiKey::OffsetFetchKey | 
             ApiKey::ListOffsetsKey | 
+            ApiKey::OffsetForLeaderEpochKey | 
             ApiKey::FetchKey | 
             ApiKey::OffsetCommitKey | 
             ApiKey::LeaveGroupKey | 
             ApiKey::InitProducerIdKey | 
+            ApiKey::EndTxnKey | 
+            ApiKey::AddOffsetsToTxnKey | 
+            ApiKey::TxnOffsetCommitKey | 
             ApiKey::ProduceKey => Ok(SerDe::new(*self as i16, call_state)?),
+            // versions 4+ are meant for brokers only, clients use up to 3
+            ApiKey::AddPartitionsToTxnKey => Ok(SerDe::with_max_version(*self as i16, call_state, 3)?),
             _ => Err(Box::new(KafkaCallerError::new("Unsupported ApiKey")))
         }
     }
@@ -86,11 +97,17 @@ impl<Req, Res> SerDe<Req, Res>
         Res: Encodable + Decodable + Default + Message + HeaderVersion
 {
     pub fn new(api_key: i16, state_for_version: Option<&CallState>) -> Result<Self, Box<dyn Error>> {
+        Self::with_max_version(api_key, state_for_version, Req::VERSIONS.max)
+    }
+
+    // highest version supported by both sides, but not above max version
+    pub fn with_max_version(api_key: i16, state_for_version: Option<&CallState>, max_version: i16) -> Result<Self, Box<dyn Error>> {
+        let max_version = min(Req::VERSIONS.max, max_version);
         let used_version = 
             match state_for_version {
                 Some(call_state) => {
                     min(
-                        Req::VERSIONS.max, 
+                        max_version, 
                     call_state.broker_api_versions
                             .get(&api_key)
                             .ok_or(KafkaCallerError::new(&format!("Could not find broker api version api key '{}'", &api_key)))?
@@ -99,7 +116,7 @@ impl<Req, Res> SerDe<Req, Res>
                             .ok_or(KafkaCallerError::new(&format!("Could not retrieve max value for broker api for api key '{}'", &api_key)))?
                     )
                 },
-                None => Req::VERSIONS.max
+                None => max_version
             };
         Ok (
             Self {
@@ -0,0 +1,40 @@
+use std::error::Error;
+use kafka_protocol::{messages::{AddOffsetsToTxnRequest, AddOffsetsToTxnResponse, GroupId, ProducerId, TransactionalId}, protocol::Builder};
+
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+
+use super::{CreateRequest, ProcessResponse};
+
+impl CreateRequest<AddOffsetsToTxnRequest> for AddOffsetsToTxnRequest {
+    fn create_request(&self, state: &CallState) -> Result<AddOffsetsToTxnRequest, Box<dyn Error>> {
+        let transactional_id = 
+            state.configuration
+                .transactional_id()?
+                .ok_or(KafkaCallerError::new("AddOffsetsToTxn requires transactional id"))?;
+        let group_metadata = 
+            state.transaction_group_metadata
+                .as_ref()
+                .ok_or(KafkaCallerError::new("AddOffsetsToTxn requires consumer group metadata"))?;
+
+        Ok(
+            AddOffsetsToTxnRequest::builder()
+                .transactional_id(TransactionalId(to_kafka_str(&transactional_id)))
+                .producer_id(ProducerId(state.producer_id))
+                .producer_epoch(state.producer_epoch)
+                .group_id(GroupId(to_kafka_str(&group_metadata.group_id)))
+                .build()?
+        )
+    }
+}
+
+impl ProcessResponse<AddOffsetsToTxnResponse> for AddOffsetsToTxnResponse {
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        if self.error_code != 0 {
+            return Err(Box::new(KafkaCallerError::new(&format!("AddOffsetsToTxn response returned error code: '{}'", self.error_code))));
+        };
+
+        state.transaction_offsets_added = true;
+
+        Ok(())
+    }
+}
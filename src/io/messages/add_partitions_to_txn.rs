@@ -0,0 +1,63 @@
+use std::error::Error;
+use indexmap::IndexMap;
+use kafka_protocol::{messages::{AddPartitionsToTxnRequest, AddPartitionsToTxnResponse, ProducerId, TopicName, TransactionalId, add_partitions_to_txn_request::AddPartitionsToTxnTopic}, protocol::Builder};
+
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+
+use super::{CreateRequest, ProcessResponse};
+
+impl CreateRequest<AddPartitionsToTxnRequest> for AddPartitionsToTxnRequest {
+    // partitions of batches about to be sent, which are not part of the transaction yet
+    fn create_request(&self, state: &CallState) -> Result<AddPartitionsToTxnRequest, Box<dyn Error>> {
+        let transactional_id = 
+            state.configuration
+                .transactional_id()?
+                .ok_or(KafkaCallerError::new("AddPartitionsToTxn requires transactional id"))?;
+
+        let mut topics: IndexMap<TopicName, AddPartitionsToTxnTopic> = IndexMap::new();
+
+        for batch in state.batches_to_send.iter() {
+            let topic_partition = (batch.topic_partition.topic.clone(), batch.topic_partition.partition);
+
+            if !state.transaction_partitions.contains(&topic_partition) {
+                topics
+                    .entry(TopicName(to_kafka_str(&topic_partition.0)))
+                    .or_default()
+                    .partitions
+                    .push(topic_partition.1);
+            }
+        }
+
+        Ok(
+            AddPartitionsToTxnRequest::builder()
+                .v3_and_below_transactional_id(TransactionalId(to_kafka_str(&transactional_id)))
+                .v3_and_below_producer_id(ProducerId(state.producer_id))
+                .v3_and_below_producer_epoch(state.producer_epoch)
+                .v3_and_below_topics(topics)
+                .build()?
+        )
+    }
+}
+
+impl ProcessResponse<AddPartitionsToTxnResponse> for AddPartitionsToTxnResponse {
+    // partitions which were added are remembered even when others failed
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        let mut error_codes = Vec::new();
+
+        for (topic_name, topic_result) in self.results_by_topic_v3_and_below.iter() {
+            for (partition_index, partition_result) in topic_result.results_by_partition.iter() {
+                if partition_result.partition_error_code == 0 {
+                    state.transaction_partitions.insert((topic_name.0.to_string(), *partition_index));
+                } else {
+                    error_codes.push(format!("{}-{}: {}", topic_name.0, partition_index, partition_result.partition_error_code));
+                }
+            }
+        }
+
+        if !error_codes.is_empty() {
+            return Err(Box::new(KafkaCallerError::new(&format!("AddPartitionsToTxn response returned error codes: '{}'", error_codes.join(", ")))));
+        }
+
+        Ok(())
+    }
+}
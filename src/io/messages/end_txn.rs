@@ -0,0 +1,39 @@
+use std::error::Error;
+use kafka_protocol::{messages::{EndTxnRequest, EndTxnResponse, ProducerId, TransactionalId}, protocol::Builder};
+
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+
+use super::{CreateRequest, ProcessResponse};
+
+impl CreateRequest<EndTxnRequest> for EndTxnRequest {
+    fn create_request(&self, state: &CallState) -> Result<EndTxnRequest, Box<dyn Error>> {
+        let transactional_id = 
+            state.configuration
+                .transactional_id()?
+                .ok_or(KafkaCallerError::new("EndTxn requires transactional id"))?;
+
+        Ok(
+            EndTxnRequest::builder()
+                .transactional_id(TransactionalId(to_kafka_str(&transactional_id)))
+                .producer_id(ProducerId(state.producer_id))
+                .producer_epoch(state.producer_epoch)
+                .committed(state.transaction_committed)
+                .build()?
+        )
+    }
+}
+
+impl ProcessResponse<EndTxnResponse> for EndTxnResponse {
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        if self.error_code != 0 {
+            return Err(Box::new(KafkaCallerError::new(&format!("EndTxn response returned error code: '{}'", self.error_code))));
+        };
+
+        // next transaction starts empty
+        state.transaction_partitions.clear();
+        state.transaction_offsets_added = false;
+        state.transaction_error = None;
+
+        Ok(())
+    }
+}
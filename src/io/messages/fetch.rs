@@ -1,7 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use kafka_protocol::{messages::{FetchRequest, BrokerId, fetch_request::{FetchTopic, FetchPartition}, TopicName, FetchResponse}, protocol::{Builder, Encodable, Decodable, Message, HeaderVersion}, records::{Record, RecordBatchDecoder}};
+use kafka_protocol::{messages::{FetchRequest, BrokerId, fetch_request::{FetchTopic, FetchPartition, ForgottenTopic}, TopicName, FetchResponse, fetch_response::AbortedTransaction}, protocol::{Builder, Encodable, Decodable, Message, HeaderVersion}};
 
-use crate::{utils::to_kafka_str, io::call_state::CallState};
+use crate::{utils::to_kafka_str, io::call_state::CallState, io::record_batch::{Record, RecordBatchDecoder}, io::records::{ConsumerRecord, ConsumerRecords, TopicPartition}, errors::KafkaCallerError, OffsetResetPolicy, IsolationLevel};
 
 use super::CreateRequest;
 
@@ -9,103 +10,289 @@ pub trait ProcessFetchResponse<Res>
     where
         Res: Encodable + Decodable + Default + Message + HeaderVersion,
 {
-    fn process_response(&self, state: &mut CallState) -> Result<Vec<Record>, Box<dyn Error>>;
+    fn process_response(&self, state: &mut CallState) -> Result<ConsumerRecords, Box<dyn Error>>;
 }
 
 impl CreateRequest<FetchRequest> for FetchRequest {
     fn create_request(&self, state: &crate::io::call_state::CallState) -> Result<FetchRequest, Box<dyn std::error::Error>> {
+        let fetchable_partitions = fetchable_partitions(state);
+        let max_partition_fetch_bytes = state.configuration.max_partition_fetch_bytes()?;
+        let fetch_session = state.fetch_sessions.get(&state.target_node_id).cloned().unwrap_or_default();
+
         Ok(
             FetchRequest::builder()
-                .min_bytes(8)
-                .max_bytes(4194304)
-                .max_wait_ms(1000)
+                .min_bytes(state.configuration.fetch_min_bytes()?)
+                .max_bytes(state.configuration.fetch_max_bytes()?)
+                .max_wait_ms(state.configuration.fetch_max_wait_ms()?)
                 .cluster_id(Some(to_kafka_str(&state.broker_metadata.cluster_id)))
                 .replica_id(BrokerId(-1))
+                .session_id(fetch_session.id)
+                .session_epoch(fetch_session.epoch)
+                .isolation_level(state.configuration.isolation_level()?.into())
+                .rack_id(to_kafka_str(&state.configuration.client_rack()?.unwrap_or_default()))
                 .topics(
                     state.fetch_state
                         .iter()
-                        .map(|(name, commited_offsets)|
-                            FetchTopic::builder()
-                                .topic(TopicName(to_kafka_str(name)))
-                                .topic_id(
-                                    state.broker_metadata.topics
-                                        .get(name)
-                                        .unwrap()
-                                            .id
-                                )
-                                .partitions(
-                                    commited_offsets
-                                        .iter()
-                                        .filter(|(_, offset_state)| -> bool {
-                                            offset_state.error_code == 0
-                                        })
-                                        .map(|(index, offset_state)|
-                                            FetchPartition::builder()
-                                                .partition(*index)
-                                                .fetch_offset(
-                                                    if offset_state.commited_offset == -1 {
-                                                        0
-                                                    } else {
-                                                        offset_state.commited_offset
-                                                    } 
-                                                )
-                                                .current_leader_epoch(0)
-                                                .last_fetched_epoch(-1)
-                                                .partition_max_bytes(1048576)
-                                                .build()
-                                                .unwrap()
-                                        )
-                                        .collect()
+                        .map(|(name, commited_offsets)| -> Result<FetchTopic, Box<dyn Error>> {
+                            Ok(
+                                FetchTopic::builder()
+                                    .topic(TopicName(to_kafka_str(name)))
+                                    .topic_id(
+                                        state.broker_metadata.topics
+                                            .get(name)
+                                            .ok_or(KafkaCallerError::new(&format!("Could not find metadata for topic '{}'", name)))?
+                                                .id
                                     )
-                                .build()
-                                .unwrap()
-                        )
+                                    .partitions(
+                                        commited_offsets
+                                            .iter()
+                                            .filter(|(index, _)| -> bool {
+                                                fetchable_partitions.contains_key(&(name.clone(), **index))
+                                            })
+                                            // incremental fetch sends only partitions which were added or changed since last request
+                                            .filter(|(index, offset_state)| -> bool {
+                                                !fetch_session.is_incremental() ||
+                                                    fetch_session.partitions.get(&(name.clone(), **index)) != Some(&offset_state.fetch_offset())
+                                            })
+                                            .map(|(index, offset_state)|
+                                                FetchPartition::builder()
+                                                    .partition(*index)
+                                                    .fetch_offset(offset_state.fetch_offset())
+                                                    .current_leader_epoch(state.broker_metadata.leader_epoch(name, *index))
+                                                    .last_fetched_epoch(offset_state.last_fetched_epoch)
+                                                    .partition_max_bytes(max_partition_fetch_bytes)
+                                                    .build()
+                                                    .unwrap()
+                                            )
+                                            .collect()
+                                        )
+                                    .build()?
+                            )
+                        })
+                        .collect::<Result<Vec<FetchTopic>, Box<dyn Error>>>()?
+                        .into_iter()
+                        .filter(|fetch_topic| !fetch_topic.partitions.is_empty())
                         .collect()
                 )
+                .forgotten_topics_data({
+                    // partitions which are in the session but are no longer fetched
+                    let mut forgotten_partitions: HashMap<String, Vec<i32>> = HashMap::new();
+
+                    fetch_session.partitions
+                        .keys()
+                        .filter(|topic_partition| !fetchable_partitions.contains_key(topic_partition))
+                        .for_each(|(name, index)| {
+                            forgotten_partitions
+                                .entry(name.clone())
+                                .or_default()
+                                .push(*index);
+                        });
+
+                    forgotten_partitions
+                        .into_iter()
+                        .map(|(name, partitions)| -> Result<ForgottenTopic, Box<dyn Error>> {
+                            Ok(
+                                ForgottenTopic::builder()
+                                    .topic_id(state.broker_metadata.topics.get(&name).map(|topic| topic.id).unwrap_or_default())
+                                    .topic(TopicName(to_kafka_str(&name)))
+                                    .partitions(partitions)
+                                    .build()?
+                            )
+                        })
+                        .collect::<Result<Vec<ForgottenTopic>, Box<dyn Error>>>()?
+                })
                 .build()?
         )
     }
 }
 
+// partitions which are fetched by the next request from the target broker, with their fetch offsets
+fn fetchable_partitions(state: &CallState) -> HashMap<(String, i32), i64> {
+    state.fetch_state
+        .iter()
+        .flat_map(|(name, commited_offsets)|
+            commited_offsets
+                .iter()
+                .filter(|(_, offset_state)| !offset_state.awaits_offset_listing())
+                .filter(move |(index, offset_state)| state.partition_fetch_node(name, **index, offset_state) == Some(state.target_node_id))
+                .map(move |(index, offset_state)| ((name.clone(), *index), offset_state.fetch_offset()))
+        )
+        .collect()
+}
+
 impl ProcessFetchResponse<FetchResponse> for FetchResponse {
-    fn process_response(&self, state: &mut CallState) -> Result<Vec<Record>, Box<dyn Error>> {
-        let mut out_records = Vec::<Record>::new();
+    fn process_response(&self, state: &mut CallState) -> Result<ConsumerRecords, Box<dyn Error>> {
+        match self.error_code {
+            0 => {},
+            // FETCH_SESSION_ID_NOT_FOUND, INVALID_FETCH_SESSION_EPOCH - session is dropped and next fetch is a full one
+            70 | 71 => {
+                state.fetch_sessions.remove(&state.target_node_id);
+
+                return Ok(ConsumerRecords::default());
+            },
+            _ => return Err(Box::new(KafkaCallerError::new(&format!("Fetch response returned error code: '{}'", self.error_code)))),
+        };
+
+        // state was not modified since the request was created, so this is what the broker now holds in the session
+        let sent_partitions = fetchable_partitions(state);
+        state.fetch_sessions
+            .entry(state.target_node_id)
+            .or_default()
+            .update(self.session_id, sent_partitions);
+
+        let auto_offset_reset = state.configuration.auto_offset_reset()?;
+        let isolation_level = state.configuration.isolation_level()?;
+        let mut out_records = ConsumerRecords::default();
+        let mut partition_errors = Vec::<String>::new();
 
         for fetchable_topic_response in &self.responses {
             let topic_name = state.broker_metadata.topic_name_from_id(fetchable_topic_response.topic_id)?;
 
-            fetchable_topic_response.partitions
-                .iter()
-                .for_each(|partition_data| {
-                    let partition_offset_state = 
-                        state.fetch_state
-                            .get_mut(&topic_name)
-                            .unwrap()
-                            .get_mut(&partition_data.partition_index)
-                            .unwrap();
-                    
-                    partition_offset_state.error_code = partition_data.error_code;
-
-                    let mut partition_records = 
-                        RecordBatchDecoder::decode(
-                            partition_data.records
-                                .clone()
-                                .as_mut()
-                                .unwrap()
+            for partition_data in &fetchable_topic_response.partitions {
+                let partition_offset_state = 
+                    state.fetch_state
+                        .get_mut(&topic_name)
+                        .and_then(|partitions| partitions.get_mut(&partition_data.partition_index))
+                        .ok_or(KafkaCallerError::new(&format!("Fetch response returned partition '{}-{}' which is not being fetched", topic_name, partition_data.partition_index)))?;
+
+                // leader redirects the fetch to a follower closer to the client rack, such response carries no records
+                if partition_data.preferred_read_replica.0 != -1 {
+                    partition_offset_state.preferred_read_replica = partition_data.preferred_read_replica.0;
+                }
+
+                match partition_data.error_code {
+                    0 => {},
+                    // OFFSET_OUT_OF_RANGE - position is reset by ListOffsets on next poll
+                    1 if auto_offset_reset != OffsetResetPolicy::None => {
+                        partition_offset_state.offset_reset_needed = true;
+                        continue;
+                    },
+                    1 => {
+                        partition_errors.push(format!("Fetch offset is out of range for partition '{}-{}' and no offset reset policy is set", topic_name, partition_data.partition_index));
+                        continue;
+                    },
+                    // CORRUPT_MESSAGE - position is kept, so the partition fails again on next fetch (same as java client)
+                    2 => {
+                        partition_errors.push(format!("Fetch returned corrupt message for partition '{}-{}'", topic_name, partition_data.partition_index));
+                        continue;
+                    },
+                    // UNKNOWN_TOPIC_OR_PARTITION, NOT_LEADER_OR_FOLLOWER, FENCED_LEADER_EPOCH, UNKNOWN_LEADER_EPOCH - stored metadata is stale,
+                    // partition is skipped and fetched again from leader after metadata is refreshed at the start of next poll
+                    3 | 6 | 74 | 75 => {
+                        partition_offset_state.preferred_read_replica = -1;
+                        state.metadata_refresh_needed = true;
+                        continue;
+                    },
+                    error_code => {
+                        partition_errors.push(format!("Fetch returned error code '{}' for partition '{}-{}'", error_code, topic_name, partition_data.partition_index));
+                        continue;
+                    },
+                };
+
+                // broker found out that log of the partition diverged from what was fetched before (KIP-320)
+                if partition_data.diverging_epoch.epoch != -1 || partition_data.diverging_epoch.end_offset != -1 {
+                    partition_errors.push(
+                        format!(
+                            "Log truncation detected for partition '{}-{}', fetch offset '{}' was moved back to '{}'", 
+                            topic_name, 
+                            partition_data.partition_index, 
+                            partition_offset_state.fetch_offset(), 
+                            partition_data.diverging_epoch.end_offset
                         )
-                        .unwrap();
+                    );
+                    partition_offset_state.truncate(partition_data.diverging_epoch.end_offset, partition_data.diverging_epoch.epoch);
+                    continue;
+                }
+
+                let decoded_records =
+                    match partition_data.records.clone() {
+                        Some(mut records_bytes) => RecordBatchDecoder::decode(&mut records_bytes),
+                        None => Ok(Vec::new()),
+                    };
+
+                match decoded_records {
+                    Ok(partition_records) => {
+                        // position moves past filtered records too, otherwise they would be fetched again
+                        if let Some(last_record) = partition_records.iter().max_by_key(|record| record.offset) {
+                            partition_offset_state.polled_offset = last_record.offset;
+                            partition_offset_state.last_fetched_epoch = last_record.partition_leader_epoch;
+                        }
+
+                        out_records.add(
+                            TopicPartition::new(&topic_name, partition_data.partition_index),
+                            filter_records(
+                                partition_records, 
+                                partition_data.aborted_transactions.as_deref().unwrap_or_default(), 
+                                isolation_level
+                            )
+                                .into_iter()
+                                .map(|record| ConsumerRecord::from_record(&topic_name, partition_data.partition_index, record))
+                                .collect()
+                        );
+                    },
+                    // record batch that cannot be decoded is handled the same way as CORRUPT_MESSAGE
+                    Err(decode_error) => {
+                        partition_errors.push(format!("Could not decode records of partition '{}-{}': {}", topic_name, partition_data.partition_index, decode_error));
+                    },
+                };
+            }
+        }
 
-                        partition_offset_state.polled_offset = 
-                            partition_records
-                                .iter()
-                                .map(|record| record.offset)
-                                .max()
-                                .unwrap_or(-1);
+        // errors are raised by caller once responses from all brokers are processed
+        if !partition_errors.is_empty() {
+            let fetch_error = 
+                match state.pending_fetch_error.take() {
+                    Some(previous_error) => KafkaCallerError(format!("{}; {}", previous_error.0, partition_errors.join("; "))),
+                    None => KafkaCallerError(partition_errors.join("; ")),
+                };
 
-                        out_records.append(&mut partition_records);
-                });
+            state.pending_fetch_error = Some(fetch_error);
         }
 
         Ok(out_records)
     }
+}
+
+// Removes transaction control markers, which are never returned to application (same as java client), and with read_committed
+// also records of aborted transactions. Aborted transaction starts at its first offset and ends with abort marker of its producer.
+pub(crate) fn filter_records(mut records: Vec<Record>, aborted_transactions: &[AbortedTransaction], isolation_level: IsolationLevel) -> Vec<Record> {
+    if isolation_level == IsolationLevel::ReadUncommitted {
+        records.retain(|record| !record.control);
+        return records;
+    }
+
+    records.sort_by_key(|record| record.offset);
+
+    let mut aborted_transactions = aborted_transactions.to_vec();
+    aborted_transactions.sort_by_key(|aborted_transaction| aborted_transaction.first_offset);
+    let mut aborted_transactions = aborted_transactions.into_iter().peekable();
+    let mut aborted_producer_ids = HashSet::<i64>::new();
+
+    records
+        .into_iter()
+        .filter(|record| {
+            while let Some(aborted_transaction) = aborted_transactions.next_if(|aborted_transaction| aborted_transaction.first_offset <= record.offset) {
+                aborted_producer_ids.insert(aborted_transaction.producer_id.0);
+            }
+
+            if record.control {
+                if is_abort_marker(record) {
+                    aborted_producer_ids.remove(&record.producer_id);
+                }
+
+                return false;
+            }
+
+            !(record.transactional && aborted_producer_ids.contains(&record.producer_id))
+        })
+        .collect()
+}
+
+// control record key is version (i16) followed by type (i16), where type 0 is abort and 1 is commit
+pub(crate) fn is_abort_marker(record: &Record) -> bool {
+    record.key
+        .as_ref()
+        .filter(|key| key.len() >= 4)
+        .map(|key| i16::from_be_bytes([key[2], key[3]]) == 0)
+        .unwrap_or(false)
 }
\ No newline at end of file
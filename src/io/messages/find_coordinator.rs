@@ -2,16 +2,19 @@ use std::error::Error;
 use kafka_protocol::messages::{FindCoordinatorRequest, FindCoordinatorResponse};
 use kafka_protocol::protocol::Builder;
 use crate::errors::KafkaCallerError;
-use crate::io::call_state::{CallState, Coordinator};
+use crate::io::call_state::{CallState, Coordinator, GROUP_COORDINATOR, TRANSACTION_COORDINATOR};
 use crate::io::messages::{CreateRequest, ProcessResponse};
 use crate::utils::to_kafka_str;
 
 impl CreateRequest<FindCoordinatorRequest> for FindCoordinatorRequest {
     fn create_request(&self, state: &CallState) -> Result<FindCoordinatorRequest, Box<dyn Error>> {
+        let (key_type, coordinator_key) = requested_coordinator(state)?;
+
         Ok(
             FindCoordinatorRequest::builder()
+                .key_type(key_type)
                 .coordinator_keys(
-                    vec![to_kafka_str(&state.configuration.group_id()?)]
+                    vec![to_kafka_str(&coordinator_key)]
                 )
                 .build()?
         )
@@ -24,12 +27,15 @@ impl ProcessResponse<FindCoordinatorResponse> for FindCoordinatorResponse {
             return Err(Box::new(KafkaCallerError::new(&format!("FindCoordinator response returned error code: '{}'", self.error_code))));
         };
 
-        state.coordinators.clear();
+        // response does not repeat the key type, it is the one of the request
+        let (key_type, _) = requested_coordinator(state)?;
+
+        // producer keeps transaction and group coordinators side by side
         state.coordinators
             .extend(
                 self.coordinators
                     .iter()
-                    .map(|response_coordinator: &kafka_protocol::messages::find_coordinator_response::Coordinator| -> (String, Coordinator) {
+                    .map(|response_coordinator: &kafka_protocol::messages::find_coordinator_response::Coordinator| -> ((i8, String), Coordinator) {
                         let coordinator =
                             Coordinator {
                                 group: response_coordinator.key.to_string(),
@@ -38,9 +44,21 @@ impl ProcessResponse<FindCoordinatorResponse> for FindCoordinatorResponse {
                                 port: response_coordinator.port,
                             };
 
-                        (response_coordinator.key.to_string(), coordinator)
+                        ((key_type, response_coordinator.key.to_string()), coordinator)
                     })
             );
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// transaction coordinator for transactional producer, group coordinator otherwise,
+// unless another coordinator is asked for explicitly
+fn requested_coordinator(state: &CallState) -> Result<(i8, String), Box<dyn Error>> {
+    Ok(
+        match (&state.coordinator_key, state.configuration.transactional_id()) {
+            (Some((coordinator_key, key_type)), _) => (*key_type, coordinator_key.clone()),
+            (None, Ok(Some(transactional_id))) => (TRANSACTION_COORDINATOR, transactional_id),
+            (None, _) => (GROUP_COORDINATOR, state.configuration.group_id()?),
+        }
+    )
+}
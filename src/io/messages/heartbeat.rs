@@ -1,8 +1,9 @@
-use kafka_protocol::{messages::{HeartbeatRequest, GroupId}, protocol::Builder};
+use std::error::Error;
+use kafka_protocol::{messages::{HeartbeatRequest, HeartbeatResponse, GroupId}, protocol::Builder};
 
-use crate::utils::to_kafka_str;
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
 
-use super::CreateRequest;
+use super::{CreateRequest, ProcessResponse};
 
 impl CreateRequest<HeartbeatRequest> for HeartbeatRequest {
     fn create_request(&self, state: &crate::io::call_state::CallState) -> Result<HeartbeatRequest, Box<dyn std::error::Error>> {
@@ -16,4 +17,28 @@ impl CreateRequest<HeartbeatRequest> for HeartbeatRequest {
     }
 }
 
-// heartbeat response is not preocessed and called by seporate function (if uncommented), so it does not need to impl ProcessResponse
\ No newline at end of file
+// heartbeat response is processed only when heartbeat is sent from poll, response of heartbeat thread (if uncommented) is just printed
+impl ProcessResponse<HeartbeatResponse> for HeartbeatResponse {
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        match self.error_code {
+            0 => Ok(()),
+            // REBALANCE_IN_PROGRESS, ILLEGAL_GENERATION - member has to join again with its member id.
+            // Fetch state is dropped like on leave, so offsets of the new assignment are fetched on join
+            27 | 22 => {
+                state.group_subscription.generation_id = -1;
+                state.fetch_state.clear();
+
+                Ok(())
+            },
+            // UNKNOWN_MEMBER_ID - member was removed from group and joins as a new one
+            25 => {
+                state.group_subscription.generation_id = -1;
+                state.group_subscription.member_id = String::default();
+                state.fetch_state.clear();
+
+                Ok(())
+            },
+            _ => Err(Box::new(KafkaCallerError::new(&format!("Heartbeat response returned error code: '{}'", self.error_code)))),
+        }
+    }
+}
\ No newline at end of file
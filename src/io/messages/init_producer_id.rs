@@ -1,15 +1,26 @@
-use kafka_protocol::{messages::{InitProducerIdRequest, InitProducerIdResponse}, protocol::Builder};
+use kafka_protocol::{messages::{InitProducerIdRequest, InitProducerIdResponse, ProducerId, TransactionalId}, protocol::Builder};
 
-use crate::errors::KafkaCallerError;
+use crate::{errors::KafkaCallerError, utils::to_kafka_str};
 
 use super::{CreateRequest, ProcessResponse};
 
 impl CreateRequest<InitProducerIdRequest> for InitProducerIdRequest {
-    fn create_request(&self, _state: &crate::io::call_state::CallState) -> Result<InitProducerIdRequest, Box<dyn std::error::Error>> {
+    // current producer id and epoch are sent when epoch is bumped, -1 when producer is initialized
+    fn create_request(&self, state: &crate::io::call_state::CallState) -> Result<InitProducerIdRequest, Box<dyn std::error::Error>> {
+        let transactional_id = state.configuration.transactional_id()?;
+
+        let transaction_timeout_ms = 
+            match transactional_id {
+                Some(_) => state.configuration.transaction_timeout_ms()?,
+                None => i32::MAX,
+            };
+
         Ok(
             InitProducerIdRequest::builder()
-                .transaction_timeout_ms(i32::MAX)
-                .transactional_id(None)
+                .transaction_timeout_ms(transaction_timeout_ms)
+                .transactional_id(transactional_id.map(|transactional_id| TransactionalId(to_kafka_str(&transactional_id))))
+                .producer_id(ProducerId(state.producer_id))
+                .producer_epoch(state.producer_epoch)
                 .build()?
         )
     }
@@ -22,6 +33,9 @@ impl ProcessResponse<InitProducerIdResponse> for InitProducerIdResponse {
         };
 
         state.producer_id = self.producer_id.0;
+        state.producer_epoch = self.producer_epoch;
+        state.producer_sequences.clear();
+        state.producer_epoch_bump_needed = false;
 
         Ok(())
     }
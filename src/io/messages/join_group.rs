@@ -17,8 +17,10 @@ impl CreateRequest<JoinGroupRequest> for JoinGroupRequest {
             builder
                 .protocol_type(StrBytes::from_str("consumer"))
                 .group_id(GroupId(to_kafka_str(&state.configuration.group_id()?)))
-                .rebalance_timeout_ms(30500)
-                .session_timeout_ms(30000)
+                // same as java client, members have max poll interval to rejoin during rebalance
+                .rebalance_timeout_ms(state.configuration.max_poll_interval_ms()?)
+                // heartbeats are sent by background thread, so session timeout is independent of how often application polls
+                .session_timeout_ms(state.configuration.session_timeout_ms()?)
                 .reason(Some(to_kafka_str("")));
 
             
@@ -1,7 +1,7 @@
 use std::error::Error;
 use kafka_protocol::{messages::{LeaveGroupRequest, LeaveGroupResponse, GroupId, leave_group_request::MemberIdentity}, protocol::Builder};
 
-use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+use crate::{io::call_state::{CallState, GroupSubscription}, errors::KafkaCallerError, utils::to_kafka_str};
 
 use super::{CreateRequest, ProcessResponse};
 
@@ -25,12 +25,16 @@ impl CreateRequest<LeaveGroupRequest> for LeaveGroupRequest {
 }
 
 impl ProcessResponse<LeaveGroupResponse> for LeaveGroupResponse {
-    fn process_response(&self, _state: &mut CallState) -> Result<(), Box<dyn Error>> {
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        // membership is dropped even when broker returns error, e.g. when member was already removed from the group.
+        // Fetch state is dropped too, as partitions are assigned again (and their offsets fetched) on next join
+        state.group_subscription = GroupSubscription::default();
+        state.fetch_state.clear();
+
         if self.error_code != 0 {
             return Err(Box::new(KafkaCallerError::new(&format!("LeaveGroup response returned error code: '{}'", self.error_code))));
         };
 
-        // LeaveGroup response is not processed atm ...
         Ok(())
     }
 }
\ No newline at end of file
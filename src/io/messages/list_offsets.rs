@@ -1,42 +1,51 @@
 use kafka_protocol::{messages::{ListOffsetsRequest, list_offsets_request::{ListOffsetsTopic, ListOffsetsPartition}, TopicName, ListOffsetsResponse}, protocol::Builder};
 
-use crate::utils::to_kafka_str;
+use crate::{utils::to_kafka_str, errors::KafkaCallerError, io::call_state::PartitionOffsetState, OffsetResetPolicy};
 
 use super::{CreateRequest, ProcessResponse};
 
+// asks leader of partitions (target node in call state) for their offsets
 impl CreateRequest<ListOffsetsRequest> for ListOffsetsRequest {
     fn create_request(&self, state: &crate::io::call_state::CallState) -> Result<ListOffsetsRequest, Box<dyn std::error::Error>> {
+        let auto_offset_reset = state.configuration.auto_offset_reset()?;
+
         Ok(
             ListOffsetsRequest::builder()
                 .replica_id(kafka_protocol::messages::BrokerId(-1))
-                .isolation_level(0)
+                // with read_committed the latest offset is the last stable offset
+                .isolation_level(state.configuration.isolation_level()?.into())
                 .topics(
                     state.connected_topics
                         .iter()
-                        .map(|topic_name| 
-                            ListOffsetsTopic::builder()
-                                .name(TopicName(to_kafka_str(topic_name)))
-                                .partitions(
-                                    state.fetch_state
-                                        .get(topic_name)
-                                        .as_ref()
-                                        .unwrap()
-                                        .keys()
-                                        .copied()
-                                        .map(|key|
-                                            ListOffsetsPartition::builder()
-                                                .partition_index(key)
-                                                // this is -2 because it is probably something like "timestamp-2"
-                                                // but the way I use this api this is enough, for -1 it will not return proper offset on first call
-                                                .timestamp(-2)
-                                                .build()
-                                                .unwrap()
-                                        )
-                                        .collect()
-                                )
-                                .build()
-                                .unwrap()
-                        )
+                        .map(|topic_name| -> Result<ListOffsetsTopic, Box<dyn std::error::Error>> {
+                            Ok(
+                                ListOffsetsTopic::builder()
+                                    .name(TopicName(to_kafka_str(topic_name)))
+                                    .partitions(
+                                        state.fetch_state
+                                            .get(topic_name)
+                                            .ok_or(KafkaCallerError::new(&format!("Could not find fetch state for topic '{}'", topic_name)))?
+                                            .iter()
+                                            .filter(|(index, _)| 
+                                                state.broker_metadata.partition(topic_name, **index).map(|partition| partition.leader_id) == Some(state.target_node_id)
+                                            )
+                                            .map(|(index, offset_state)| -> Result<ListOffsetsPartition, Box<dyn std::error::Error>> {
+                                                Ok(
+                                                    ListOffsetsPartition::builder()
+                                                        .partition_index(*index)
+                                                        .current_leader_epoch(state.broker_metadata.leader_epoch(topic_name, *index))
+                                                        .timestamp(reset_timestamp(topic_name, *index, offset_state, auto_offset_reset)?)
+                                                        .build()?
+                                                )
+                                            })
+                                            .collect::<Result<Vec<ListOffsetsPartition>, Box<dyn std::error::Error>>>()?
+                                    )
+                                    .build()?
+                            )
+                        })
+                        .collect::<Result<Vec<ListOffsetsTopic>, Box<dyn std::error::Error>>>()?
+                        .into_iter()
+                        .filter(|topic| !topic.partitions.is_empty())
                         .collect()
                 )
                 .build()?
@@ -44,26 +53,52 @@ impl CreateRequest<ListOffsetsRequest> for ListOffsetsRequest {
     }
 }
 
+// -2 returns earliest offset and -1 latest offset. Partitions with valid committed offset use -2, so that the committed offset
+// can be moved forward when it points to already deleted records (for -1 it will not return proper offset on first call)
+fn reset_timestamp(topic_name: &str, index: i32, offset_state: &PartitionOffsetState, auto_offset_reset: OffsetResetPolicy) -> Result<i64, KafkaCallerError> {
+    if !offset_state.offset_reset_needed && offset_state.commited_offset != -1 {
+        return Ok(-2);
+    }
+
+    match auto_offset_reset {
+        OffsetResetPolicy::Earliest => Ok(-2),
+        OffsetResetPolicy::Latest => Ok(-1),
+        OffsetResetPolicy::None => Err(KafkaCallerError::new(&format!("No valid offset found for partition '{}-{}' and no offset reset policy is set", topic_name, index))),
+    }
+}
+
 impl ProcessResponse<ListOffsetsResponse> for ListOffsetsResponse {
     fn process_response(&self, state: &mut crate::io::call_state::CallState) -> Result<(), Box<dyn std::error::Error>> {
-        self.topics
-            .iter()
-            .for_each(|topic_offsets| {
-                topic_offsets.partitions
-                    .iter()
-                    .for_each(|partition| {
-                        let fetch_state = 
-                            state.fetch_state
-                                .get_mut(&topic_offsets.name.to_string())
-                                .unwrap()
-                                .get_mut(&partition.partition_index)
-                                .unwrap();
-                            
-                        if partition.offset > fetch_state.commited_offset {
-                            fetch_state.commited_offset = partition.offset;
-                        }
-                    });
-            });
+        for topic_offsets in &self.topics {
+            for partition in &topic_offsets.partitions {
+                let fetch_state = 
+                    state.fetch_state
+                        .get_mut(&topic_offsets.name.to_string())
+                        .and_then(|partitions| partitions.get_mut(&partition.partition_index))
+                        .ok_or(KafkaCallerError::new(&format!("ListOffsets response returned partition '{}-{}' which is not being fetched", topic_offsets.name.0, partition.partition_index)))?;
+
+                match partition.error_code {
+                    0 => fetch_state.offset_listing_needed = false,
+                    // UNKNOWN_TOPIC_OR_PARTITION, NOT_LEADER_OR_FOLLOWER, FENCED_LEADER_EPOCH, UNKNOWN_LEADER_EPOCH - leader moved,
+                    // partition is not fetched until metadata is refreshed at the start of next poll and its offsets are listed again
+                    3 | 6 | 74 | 75 => {
+                        fetch_state.offset_listing_needed = true;
+                        state.metadata_refresh_needed = true;
+                        continue;
+                    },
+                    error_code => return Err(Box::new(KafkaCallerError::new(&format!("ListOffsets response returned error code '{}' for partition '{}-{}'", error_code, topic_offsets.name.0, partition.partition_index)))),
+                }
+
+                if fetch_state.offset_reset_needed || fetch_state.commited_offset == -1 {
+                    fetch_state.commited_offset = partition.offset;
+                    fetch_state.polled_offset = -1;
+                    fetch_state.consumed_offset = -1;
+                    fetch_state.offset_reset_needed = false;
+                } else if partition.offset > fetch_state.commited_offset {
+                    fetch_state.commited_offset = partition.offset;
+                }
+            }
+        }
 
         Ok(())
     }
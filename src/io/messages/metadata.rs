@@ -34,7 +34,7 @@ impl CreateRequest<MetadataRequest> for MetadataRequest {
 
 impl ProcessResponse<MetadataResponse> for MetadataResponse {
     fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
-        state.broker_metadata =
+        let broker_metadata =
             BrokerMetadata {
                 cluster_id:
                     self.cluster_id
@@ -51,6 +51,7 @@ impl ProcessResponse<MetadataResponse> for MetadataResponse {
                                     id: broker_id.0,
                                     host: metadata_response_broker.host.to_string(),
                                     port: metadata_response_broker.port,
+                                    rack: metadata_response_broker.rack.as_ref().map(|rack| rack.to_string()),
                                 };
 
                             (broker_id.0, broker)
@@ -72,6 +73,9 @@ impl ProcessResponse<MetadataResponse> for MetadataResponse {
                                                     Partition {
                                                         index: partition_metadata.partition_index,
                                                         leader_id: partition_metadata.leader_id.0,
+                                                        leader_epoch: partition_metadata.leader_epoch,
+                                                        replica_ids: partition_metadata.replica_nodes.iter().map(|broker_id| broker_id.0).collect(),
+                                                        isr_ids: partition_metadata.isr_nodes.iter().map(|broker_id| broker_id.0).collect(),
                                                     };
 
                                                 (partition_metadata.partition_index, partition)
@@ -83,6 +87,22 @@ impl ProcessResponse<MetadataResponse> for MetadataResponse {
                         })
                         .collect(),
             };
+        let previous_broker_metadata = std::mem::replace(&mut state.broker_metadata, broker_metadata);
+        state.metadata_refresh_needed = false;
+
+        // fetched data could be lost in log truncation when leader changed, so position is validated before next fetch
+        for (topic_name, partitions) in state.fetch_state.iter_mut() {
+            for (index, offset_state) in partitions.iter_mut() {
+                let previous_leader_epoch = previous_broker_metadata.leader_epoch(topic_name, *index);
+
+                if offset_state.last_fetched_epoch != -1 && 
+                    previous_leader_epoch != -1 &&
+                    previous_leader_epoch != state.broker_metadata.leader_epoch(topic_name, *index) 
+                {
+                    offset_state.position_validation_needed = true;
+                }
+            }
+        }
 
         Ok(())
     }
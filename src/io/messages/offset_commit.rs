@@ -1,7 +1,7 @@
 use std::error::Error;
 use kafka_protocol::{messages::{OffsetCommitRequest, GroupId, offset_commit_request::{OffsetCommitRequestTopic, OffsetCommitRequestPartition}, TopicName, OffsetCommitResponse}, protocol::Builder};
 
-use crate::{utils::to_kafka_str, io::call_state::CallState};
+use crate::{utils::to_kafka_str, io::call_state::CallState, errors::KafkaCallerError};
 
 use super::{CreateRequest, ProcessResponse};
 
@@ -23,14 +23,15 @@ use super::{CreateRequest, ProcessResponse};
                         .partitions(
                            connection_state
                               .iter()
+                              // partitions without any new consumed record have nothing to commit
                               .filter(|(_, partition_offset_state)| -> bool {
-                                 partition_offset_state.error_code == 0
+                                 partition_offset_state.has_uncommitted_offset()
                               }) 
                               .map(|(index, partition_offset_state)| 
                                  OffsetCommitRequestPartition::builder()
                                     .partition_index(*index)
-                                    .committed_offset(partition_offset_state.polled_offset + 1)
-                                    .committed_leader_epoch(-1)
+                                    .committed_offset(partition_offset_state.consumed_offset + 1)
+                                    .committed_leader_epoch(partition_offset_state.consumed_leader_epoch)
                                     .build()
                                     .unwrap()
                               )
@@ -47,8 +48,40 @@ use super::{CreateRequest, ProcessResponse};
  }
 
  impl ProcessResponse<OffsetCommitResponse> for OffsetCommitResponse {
-   fn process_response(&self, _state: &mut CallState) -> Result<(), Box<dyn Error>> {
-       // OffsetCommit response is not processed atm ...
-       Ok(())
+   fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+      for topic in &self.topics {
+         for partition in &topic.partitions {
+            match partition.error_code {
+               0 => {},
+               // REBALANCE_IN_PROGRESS, ILLEGAL_GENERATION - member has to join again with its member id (same as for heartbeat).
+               // Offsets are not committed, fetch state with them is dropped, so records are consumed again from committed offsets
+               27 | 22 => {
+                  state.group_subscription.generation_id = -1;
+                  state.fetch_state.clear();
+
+                  return Ok(());
+               },
+               // UNKNOWN_MEMBER_ID - member was removed from group and joins as a new one
+               25 => {
+                  state.group_subscription.generation_id = -1;
+                  state.group_subscription.member_id = String::default();
+                  state.fetch_state.clear();
+
+                  return Ok(());
+               },
+               error_code => return Err(Box::new(KafkaCallerError::new(&format!("OffsetCommit response returned error code '{}' for partition '{}-{}'", error_code, topic.name.0, partition.partition_index)))),
+            }
+
+            if let Some(partition_offset_state) = 
+               state.fetch_state
+                  .get_mut(&topic.name.to_string())
+                  .and_then(|partitions| partitions.get_mut(&partition.partition_index))
+            {
+               partition_offset_state.commited_offset = partition_offset_state.consumed_offset + 1;
+            }
+         }
+      }
+
+      Ok(())
    }
 }
\ No newline at end of file
@@ -0,0 +1,96 @@
+use std::error::Error;
+use kafka_protocol::{messages::{OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse, BrokerId, TopicName, offset_for_leader_epoch_request::{OffsetForLeaderTopic, OffsetForLeaderPartition}}, protocol::Builder};
+
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+
+use super::{CreateRequest, ProcessResponse};
+
+// asks leader of partitions (target node in call state) for end offset of last fetched epoch
+impl CreateRequest<OffsetForLeaderEpochRequest> for OffsetForLeaderEpochRequest {
+    fn create_request(&self, state: &CallState) -> Result<OffsetForLeaderEpochRequest, Box<dyn Error>> {
+        Ok(
+            OffsetForLeaderEpochRequest::builder()
+                .replica_id(BrokerId(-1))
+                .topics(
+                    state.fetch_state
+                        .iter()
+                        .map(|(name, partitions)| 
+                            OffsetForLeaderTopic::builder()
+                                .topic(TopicName(to_kafka_str(name)))
+                                .partitions(
+                                    partitions
+                                        .iter()
+                                        .filter(|(index, offset_state)| -> bool {
+                                            offset_state.position_validation_needed &&
+                                                state.broker_metadata.partition(name, **index).map(|partition| partition.leader_id) == Some(state.target_node_id)
+                                        })
+                                        .map(|(index, offset_state)|
+                                            OffsetForLeaderPartition::builder()
+                                                .partition(*index)
+                                                .current_leader_epoch(state.broker_metadata.leader_epoch(name, *index))
+                                                .leader_epoch(offset_state.last_fetched_epoch)
+                                                .build()
+                                                .unwrap()
+                                        )
+                                        .collect()
+                                )
+                                .build()
+                                .unwrap()
+                        )
+                        .filter(|topic| !topic.partitions.is_empty())
+                        .collect()
+                )
+                .build()?
+        )
+    }
+}
+
+// Leader returns end offset of the requested epoch in its log. When it is lower than fetch position, records after it were lost
+// (log truncation after unclean leader election), position is moved back and truncation is reported to application.
+impl ProcessResponse<OffsetForLeaderEpochResponse> for OffsetForLeaderEpochResponse {
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        let mut truncation_errors = Vec::<String>::new();
+
+        for topic in &self.topics {
+            for epoch_end_offset in &topic.partitions {
+                let offset_state = 
+                    state.fetch_state
+                        .get_mut(&topic.topic.to_string())
+                        .and_then(|partitions| partitions.get_mut(&epoch_end_offset.partition))
+                        .ok_or(KafkaCallerError::new(&format!("OffsetForLeaderEpoch response returned partition '{}-{}' which is not being fetched", topic.topic.0, epoch_end_offset.partition)))?;
+
+                match epoch_end_offset.error_code {
+                    0 => {},
+                    // NOT_LEADER_OR_FOLLOWER, FENCED_LEADER_EPOCH, UNKNOWN_LEADER_EPOCH - validated again after metadata refresh
+                    6 | 74 | 75 => {
+                        state.metadata_refresh_needed = true;
+                        continue;
+                    },
+                    error_code => return Err(Box::new(KafkaCallerError::new(&format!("OffsetForLeaderEpoch response returned error code '{}' for partition '{}-{}'", error_code, topic.topic.0, epoch_end_offset.partition)))),
+                };
+
+                offset_state.position_validation_needed = false;
+
+                if epoch_end_offset.leader_epoch == -1 || epoch_end_offset.end_offset == -1 {
+                    // leader does not know the epoch at all, so position cannot be trusted and is reset
+                    truncation_errors.push(format!("Log truncation detected for partition '{}-{}', leader does not know epoch '{}', position is reset", topic.topic.0, epoch_end_offset.partition, offset_state.last_fetched_epoch));
+                    offset_state.offset_reset_needed = true;
+                } else if epoch_end_offset.end_offset < offset_state.fetch_offset() {
+                    truncation_errors.push(format!("Log truncation detected for partition '{}-{}', fetch offset '{}' was moved back to '{}'", topic.topic.0, epoch_end_offset.partition, offset_state.fetch_offset(), epoch_end_offset.end_offset));
+                    offset_state.truncate(epoch_end_offset.end_offset, epoch_end_offset.leader_epoch);
+                }
+            }
+        }
+
+        // reported the same way as partition errors from fetch
+        if !truncation_errors.is_empty() {
+            state.pending_fetch_error = 
+                match state.pending_fetch_error.take() {
+                    Some(previous_error) => Some(KafkaCallerError(format!("{}; {}", previous_error.0, truncation_errors.join("; ")))),
+                    None => Some(KafkaCallerError(truncation_errors.join("; "))),
+                };
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
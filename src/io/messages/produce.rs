@@ -1,97 +1,85 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::collections::HashMap;
 
 use bytes::{Bytes, BytesMut};
 use indexmap::IndexMap;
-use kafka_protocol::{messages::{ProduceRequest, TopicName, produce_request::{TopicProduceData, PartitionProduceData}, ProduceResponse}, protocol::Builder, records::{RecordBatchEncoder, Compression, RecordEncodeOptions, Record, TimestampType}};
+use kafka_protocol::{messages::{ProduceRequest, TopicName, TransactionalId, produce_request::{TopicProduceData, PartitionProduceData}, ProduceResponse}, protocol::Builder, records::RecordEncodeOptions};
 
-use crate::{utils::to_kafka_str, io::records::PutRecord, errors::KafkaCallerError};
+use crate::{utils::to_kafka_str, io::{call_state::CallState, accumulator::{increment_sequence, MAX_RECORD_OVERHEAD, RECORD_BATCH_OVERHEAD}, record_batch::{Record, RecordBatchEncoder}, records::TimestampType}, errors::KafkaCallerError};
 
 use super::{CreateRequest, ProcessResponse};
 
 impl CreateRequest<ProduceRequest> for ProduceRequest {
     fn create_request(&self, state: &crate::io::call_state::CallState) -> Result<ProduceRequest, Box<dyn std::error::Error>> {
-        let now: i64 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
-        
         Ok(
             ProduceRequest::builder()
-                .acks(1)
-                .transactional_id(None)
-                .timeout_ms(30000)
+                .acks(state.configuration.acks()?.into())
+                .transactional_id(
+                    state.configuration
+                        .transactional_id()?
+                        .map(|transactional_id| TransactionalId(to_kafka_str(&transactional_id)))
+                )
+                .timeout_ms(state.configuration.request_timeout_ms()?)
                 .topic_data({
-                    let mut records_by_topic: HashMap<String, Vec<PutRecord>> = HashMap::default();
+                    let compression = state.configuration.compression_type()?.into();
+                    let compression_level = state.configuration.compression_level()?;
 
-                    state.records_to_send
-                        .iter()
-                        .for_each(|put_record| {
-                            records_by_topic
-                                .entry(put_record.topic.clone())
-                                .and_modify(|vec_put_record| 
-                                    vec_put_record.push(put_record.clone())).or_insert(vec!(put_record.clone())
-                                );
-                        });
-
-                        let mut producer_topic_data = IndexMap::new();
-
-                        for topic_name in records_by_topic.keys() {
-                            let partition = 
-                                state.broker_metadata
-                                    .topics
-                                        .get(topic_name)
-                                        .ok_or(KafkaCallerError::new(&format!("Could not find topic with name '{}' in stored metadata", topic_name)))?
-                                    .partitions
-                                        .values()
-                                        .by_ref()
-                                        .next()
-                                        .ok_or(KafkaCallerError::new(&format!("No partitions found for topic '{}' in stored metadata", topic_name)))?;
-                
-                            let mut partition_produce_data = Vec::<PartitionProduceData>::new();
-                
-                            let mut record_data = Vec::<Record>::new();
-                
-                            for (sequence, put_record) in 
-                                records_by_topic
-                                    .get(topic_name)
-                                    .ok_or(KafkaCallerError::new(&format!("No records to put found for topic: '{}'", topic_name)))?
-                                    .iter()
-                                    .enumerate() 
-                            {
-
-                                let mut one_record_data: Record = put_record.into();
-                                one_record_data.producer_id = state.producer_id;
-                                one_record_data.offset = sequence as i64;
-                                one_record_data.sequence = sequence as i32;
-                                one_record_data.control = false;
-                                one_record_data.timestamp = now;
-                                one_record_data.timestamp_type = TimestampType::Creation;
-                
-                                record_data.push(one_record_data);
-                            }
-                
-                            let encode_options = 
-                                RecordEncodeOptions {
-                                    compression: Compression::Snappy,
-                                    version: 2,
+                    // each batch holds records of one partition
+                    let mut partition_data_by_topic: IndexMap<String, Vec<PartitionProduceData>> = IndexMap::default();
+
+                    for batch in state.batches_to_send.iter() {
+                        let mut record_data = Vec::<Record>::new();
+
+                        for (index, (put_record, timestamp)) in batch.records.iter().zip(batch.timestamps.iter()).enumerate() {
+                            let mut one_record_data: Record = put_record.into();
+                            one_record_data.producer_id = state.producer_id;
+                            one_record_data.producer_epoch = state.producer_epoch;
+                            one_record_data.transactional = state.configuration.transactional_id()?.is_some();
+                            one_record_data.offset = index as i64;
+                            one_record_data.sequence = 
+                                if batch.base_sequence == -1 {
+                                    -1
+                                } else {
+                                    increment_sequence(batch.base_sequence, index as i32)
                                 };
-                
-                            let record_bytes = &mut BytesMut::default();
-                            RecordBatchEncoder::encode(record_bytes, record_data.iter(), &encode_options)?;
-                
-                            partition_produce_data.push(
+                            one_record_data.control = false;
+                            one_record_data.timestamp = *timestamp;
+                            one_record_data.timestamp_type = TimestampType::CreateTime;
+            
+                            record_data.push(one_record_data);
+                        }
+            
+                        let encode_options = 
+                            RecordEncodeOptions {
+                                compression,
+                                version: 2,
+                            };
+            
+                        let record_bytes = &mut BytesMut::default();
+                        RecordBatchEncoder::encode_with_level(record_bytes, record_data.iter(), &encode_options, compression_level)?;
+
+                        partition_data_by_topic
+                            .entry(batch.topic_partition.topic.clone())
+                            .or_default()
+                            .push(
                                 PartitionProduceData::builder()
-                                    .index(partition.index)
+                                    .index(batch.topic_partition.partition)
                                     .records(Some(Bytes::copy_from_slice(record_bytes)))
                                     .build()?
                             );
-                
-                            producer_topic_data.insert(
-                                TopicName(to_kafka_str(topic_name)),
-                                    TopicProduceData::builder()
-                                        .partition_data(partition_produce_data)
-                                        .build()?
-                            );
-                        }
+                    }
+
+                    let mut producer_topic_data = IndexMap::new();
+
+                    for (topic_name, partition_produce_data) in partition_data_by_topic {
+                        producer_topic_data.insert(
+                            TopicName(to_kafka_str(&topic_name)),
+                                TopicProduceData::builder()
+                                    .partition_data(partition_produce_data)
+                                    .build()?
+                        );
+                    }
 
-                        producer_topic_data
+                    producer_topic_data
                 })
                 .build()?
         )
@@ -99,8 +87,103 @@ impl CreateRequest<ProduceRequest> for ProduceRequest {
 }
 
 impl ProcessResponse<ProduceResponse> for ProduceResponse {
-    fn process_response(&self, _state: &mut crate::io::call_state::CallState) -> Result<(), Box<dyn std::error::Error>> {
-        // Produce response is not processed atm ...
+    // Every answered batch is completed here, failures are passed to records of the batch and do not fail the whole call.
+    fn process_response(&self, state: &mut CallState) -> Result<(), Box<dyn std::error::Error>> {
+        for (topic_name, topic_response) in self.responses.iter() {
+            for partition_response in topic_response.partition_responses.iter() {
+                let batch_position = 
+                    state.batches_to_send
+                        .iter()
+                        .position(|batch| 
+                            batch.topic_partition.topic.as_str() == &*topic_name.0 && 
+                            batch.topic_partition.partition == partition_response.index
+                        );
+
+                let batch = 
+                    match batch_position {
+                        Some(batch_position) => state.batches_to_send.swap_remove(batch_position),
+                        None => continue,
+                    };
+
+                match partition_response.error_code {
+                    0 => batch.complete(partition_response.base_offset, partition_response.log_append_time_ms),
+                    // DUPLICATE_SEQUENCE_NUMBER - batch was written by earlier attempt whose response was lost
+                    46 => batch.complete_without_offset(),
+                    // MESSAGE_TOO_LARGE - batch is over "message.max.bytes" of broker or "max.message.bytes" of topic,
+                    // it is split unless it holds only one record, which can never be written
+                    10 if batch.records.len() > 1 => state.batches_to_split.push(batch),
+                    10 => {
+                        let error = KafkaCallerError::new(&format!(
+                            "Record of {} bytes for partition {} of topic '{}' is larger than broker accepts", 
+                            batch.size_bytes + MAX_RECORD_OVERHEAD + RECORD_BATCH_OVERHEAD, 
+                            partition_response.index, 
+                            topic_name.0
+                        ));
+
+                        state.transaction_error = Some(error.clone());
+                        state.producer_epoch_bump_needed |= batch.base_sequence != -1;
+                        batch.fail(error);
+                    },
+                    // INVALID_RECORD - broker tells which records of the batch were rejected
+                    87 => {
+                        let record_errors: HashMap<usize, String> = 
+                            partition_response.record_errors
+                                .iter()
+                                .map(|record_error| (
+                                    record_error.batch_index as usize,
+                                    format!(
+                                        "Record was rejected by broker: {}", 
+                                        record_error.batch_index_error_message.as_ref().map(|message| message.to_string()).unwrap_or_default()
+                                    )
+                                ))
+                                .collect();
+
+                        let error = KafkaCallerError::new(&format!(
+                            "Record was part of a batch for partition {} of topic '{}' which had invalid records: {}", 
+                            partition_response.index, 
+                            topic_name.0, 
+                            partition_response.error_message.as_ref().map(|message| message.to_string()).unwrap_or_default()
+                        ));
+
+                        state.transaction_error = Some(error.clone());
+                        state.producer_epoch_bump_needed |= batch.base_sequence != -1;
+                        batch.fail_with_record_errors(error, &record_errors);
+                    },
+                    error_code => {
+                        let error = KafkaCallerError::new(&format!(
+                            "Produce response returned error code {} for partition {} of topic '{}': {}", 
+                            error_code, 
+                            partition_response.index, 
+                            topic_name.0,
+                            partition_response.error_message.as_ref().map(|message| message.to_string()).unwrap_or_default()
+                        ));
+
+                        // UNKNOWN_TOPIC_OR_PARTITION, NOT_LEADER_OR_FOLLOWER, KAFKA_STORAGE_ERROR - leader moved
+                        if error_code == 3 || error_code == 6 || error_code == 56 {
+                            state.metadata_refresh_needed = true;
+                        }
+
+                        // OUT_OF_ORDER_SEQUENCE_NUMBER - some earlier batch was lost, UNKNOWN_PRODUCER_ID - broker does not know
+                        // the producer anymore (its records were deleted by retention), both are recovered by bumping the epoch
+                        if error_code == 45 || error_code == 59 {
+                            state.producer_epoch_bump_needed = true;
+                        }
+
+                        // retriable errors, batch is sent again until delivery timeout expires
+                        if matches!(error_code, 3 | 6 | 7 | 19 | 20 | 56 | 59) {
+                            state.batches_to_retry.push((batch, error));
+                        } else {
+                            // sequence of the partition moved past failed batch, following batches would be rejected
+                            // as out of order, so sequences start again with bumped epoch
+                            state.transaction_error = Some(error.clone());
+                            state.producer_epoch_bump_needed |= batch.base_sequence != -1;
+                            batch.fail(error);
+                        }
+                    },
+                }
+            }
+        }
+
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,74 @@
+use std::error::Error;
+use indexmap::IndexMap;
+use kafka_protocol::{messages::{TxnOffsetCommitRequest, TxnOffsetCommitResponse, GroupId, ProducerId, TopicName, TransactionalId, txn_offset_commit_request::{TxnOffsetCommitRequestTopic, TxnOffsetCommitRequestPartition}}, protocol::Builder};
+
+use crate::{io::call_state::CallState, errors::KafkaCallerError, utils::to_kafka_str};
+
+use super::{CreateRequest, ProcessResponse};
+
+impl CreateRequest<TxnOffsetCommitRequest> for TxnOffsetCommitRequest {
+    fn create_request(&self, state: &CallState) -> Result<TxnOffsetCommitRequest, Box<dyn Error>> {
+        let transactional_id = 
+            state.configuration
+                .transactional_id()?
+                .ok_or(KafkaCallerError::new("TxnOffsetCommit requires transactional id"))?;
+        let group_metadata = 
+            state.transaction_group_metadata
+                .as_ref()
+                .ok_or(KafkaCallerError::new("TxnOffsetCommit requires consumer group metadata"))?;
+
+        let mut partitions_by_topic: IndexMap<String, Vec<TxnOffsetCommitRequestPartition>> = IndexMap::new();
+
+        for (topic_partition, offset) in state.transaction_offsets.iter() {
+            partitions_by_topic
+                .entry(topic_partition.topic.clone())
+                .or_default()
+                .push(
+                    TxnOffsetCommitRequestPartition::builder()
+                        .partition_index(topic_partition.partition)
+                        .committed_offset(*offset)
+                        .committed_leader_epoch(-1)
+                        .committed_metadata(None)
+                        .build()?
+                );
+        }
+
+        let mut topics = Vec::new();
+
+        for (topic_name, partitions) in partitions_by_topic {
+            topics.push(
+                TxnOffsetCommitRequestTopic::builder()
+                    .name(TopicName(to_kafka_str(&topic_name)))
+                    .partitions(partitions)
+                    .build()?
+            );
+        }
+
+        Ok(
+            TxnOffsetCommitRequest::builder()
+                .transactional_id(TransactionalId(to_kafka_str(&transactional_id)))
+                .group_id(GroupId(to_kafka_str(&group_metadata.group_id)))
+                .producer_id(ProducerId(state.producer_id))
+                .producer_epoch(state.producer_epoch)
+                .generation_id(group_metadata.generation_id)
+                .member_id(to_kafka_str(&group_metadata.member_id))
+                .group_instance_id(group_metadata.group_instance_id.as_ref().map(|group_instance_id| to_kafka_str(group_instance_id)))
+                .topics(topics)
+                .build()?
+        )
+    }
+}
+
+impl ProcessResponse<TxnOffsetCommitResponse> for TxnOffsetCommitResponse {
+    fn process_response(&self, _state: &mut CallState) -> Result<(), Box<dyn Error>> {
+        for topic in &self.topics {
+            for partition in &topic.partitions {
+                if partition.error_code != 0 {
+                    return Err(Box::new(KafkaCallerError::new(&format!("TxnOffsetCommit response returned error code '{}' for partition '{}-{}'", partition.error_code, topic.name.0, partition.partition_index))));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
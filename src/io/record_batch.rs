@@ -0,0 +1,386 @@
+use std::error::Error;
+use std::io::Write;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use kafka_protocol::compression::{Compressor, Decompressor, Gzip, Lz4, Snappy, Zstd};
+use kafka_protocol::records::{Compression, RecordEncodeOptions};
+
+use crate::errors::KafkaCallerError;
+use crate::io::accumulator::{increment_sequence, RECORD_BATCH_OVERHEAD};
+use crate::io::records::{Headers, TimestampType};
+
+// Record batch (magic 2) is encoded and decoded here instead of by kafka-protocol, whose Record keeps headers in a map
+// and so loses repeated header keys and their order. Compression codecs of kafka-protocol are still used, except for
+// compression with level set by configuration, as they always use the default level of the codec.
+
+// base offset (i64) and batch length (i32) precede the part of the batch counted by its length
+const BATCH_LENGTH_END: usize = 12;
+// partition leader epoch (i32), magic (i8) and crc (u32) precede the part of the batch covered by crc
+const CRC_COVERED_START: usize = BATCH_LENGTH_END + 9;
+// magic is at the same position in record batch and in message of legacy message set (after its offset, size and crc)
+const MAGIC_OFFSET: usize = 16;
+
+const COMPRESSION_MASK: i16 = 0x07;
+const LOG_APPEND_TIME_FLAG: i16 = 0x08;
+const TRANSACTIONAL_FLAG: i16 = 0x10;
+const CONTROL_FLAG: i16 = 0x20;
+
+// same fields as Record of kafka-protocol, with headers in the order they were added
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub transactional: bool,
+    pub control: bool,
+    pub partition_leader_epoch: i32,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub timestamp_type: TimestampType,
+    pub offset: i64,
+    pub sequence: i32,
+    pub timestamp: i64,
+    pub key: Option<Bytes>,
+    pub value: Option<Bytes>,
+    pub headers: Headers,
+}
+
+pub(crate) struct RecordBatchEncoder;
+
+impl RecordBatchEncoder {
+    // All records are written into one batch, fields of the batch (producer, transactional and control flags,
+    // timestamp type) are taken from the first record. Producer writes records of one partition batch at a time.
+    pub fn encode<'a>(buf: &mut BytesMut, records: impl Iterator<Item = &'a Record>, options: &RecordEncodeOptions) -> Result<(), Box<dyn Error>> {
+        Self::encode_with_level(buf, records, options, None)
+    }
+
+    // same as encode, records are compressed with given level of the codec instead of its default one
+    pub fn encode_with_level<'a>(
+        buf: &mut BytesMut, 
+        records: impl Iterator<Item = &'a Record>, 
+        options: &RecordEncodeOptions, 
+        compression_level: Option<i32>
+    ) -> Result<(), Box<dyn Error>> {
+        if options.version != 2 {
+            return Err(Box::new(KafkaCallerError::new(&format!("Record batch version '{}' is not supported, only version 2 is", options.version))));
+        }
+
+        let records: Vec<&Record> = records.collect();
+
+        let first_record =
+            match records.first() {
+                Some(first_record) => *first_record,
+                None => return Ok(()),
+            };
+
+        let base_offset = records.iter().map(|record| record.offset).min().unwrap_or_default();
+        let last_offset = records.iter().map(|record| record.offset).max().unwrap_or_default();
+        let base_timestamp = records.iter().map(|record| record.timestamp).min().unwrap_or_default();
+        let max_timestamp = records.iter().map(|record| record.timestamp).max().unwrap_or_default();
+
+        let mut records_bytes = BytesMut::new();
+        for record in records.iter() {
+            encode_record(&mut records_bytes, record, base_offset, base_timestamp);
+        }
+
+        let mut attributes =
+            match options.compression {
+                Compression::None => 0,
+                Compression::Gzip => 1,
+                Compression::Snappy => 2,
+                Compression::Lz4 => 3,
+                Compression::Zstd => 4,
+            };
+        if first_record.timestamp_type == TimestampType::LogAppendTime {
+            attributes |= LOG_APPEND_TIME_FLAG;
+        }
+        if first_record.transactional {
+            attributes |= TRANSACTIONAL_FLAG;
+        }
+        if first_record.control {
+            attributes |= CONTROL_FLAG;
+        }
+
+        // part of the batch covered by crc
+        let mut batch_body = BytesMut::new();
+        batch_body.put_i16(attributes);
+        batch_body.put_i32((last_offset - base_offset) as i32);
+        batch_body.put_i64(base_timestamp);
+        batch_body.put_i64(max_timestamp);
+        batch_body.put_i64(first_record.producer_id);
+        batch_body.put_i16(first_record.producer_epoch);
+        batch_body.put_i32(first_record.sequence);
+        batch_body.put_i32(records.len() as i32);
+
+        match (options.compression, compression_level) {
+            (Compression::None, _) => batch_body.put_slice(&records_bytes),
+            (compression, Some(level)) => batch_body.put_slice(&compress_with_level(compression, level, &records_bytes)?),
+            (Compression::Gzip, None) => Gzip::compress(&mut batch_body, |compressed| { compressed.put_slice(&records_bytes); Ok(()) })?,
+            (Compression::Snappy, None) => Snappy::compress(&mut batch_body, |compressed| { compressed.put_slice(&records_bytes); Ok(()) })?,
+            (Compression::Lz4, None) => Lz4::compress(&mut batch_body, |compressed| { compressed.put_slice(&records_bytes); Ok(()) })?,
+            (Compression::Zstd, None) => Zstd::compress(&mut batch_body, |compressed| { compressed.put_slice(&records_bytes); Ok(()) })?,
+        }
+
+        buf.put_i64(base_offset);
+        buf.put_i32((CRC_COVERED_START - BATCH_LENGTH_END + batch_body.len()) as i32);
+        buf.put_i32(first_record.partition_leader_epoch);
+        buf.put_i8(2);
+        // CRC-32C (Castagnoli) of everything following the crc field
+        buf.put_u32(crc32c::crc32c(&batch_body));
+        buf.put_slice(&batch_body);
+
+        Ok(())
+    }
+}
+
+pub(crate) struct RecordBatchDecoder;
+
+impl RecordBatchDecoder {
+    // Records of all complete batches. Broker may end fetched data with a partial batch when it reaches the size limit,
+    // that batch is skipped and fetched again from its offset by the next fetch.
+    pub fn decode(buf: &mut Bytes) -> Result<Vec<Record>, Box<dyn Error>> {
+        let mut records = Vec::new();
+
+        while buf.remaining() >= BATCH_LENGTH_END {
+            let batch_length = i32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+
+            if batch_length < 0 {
+                return Err(Box::new(KafkaCallerError::new(&format!("Record batch has invalid length '{}'", batch_length))));
+            }
+
+            if buf.remaining() < BATCH_LENGTH_END + batch_length as usize {
+                break;
+            }
+
+            decode_batch(buf.split_to(BATCH_LENGTH_END + batch_length as usize), &mut records)?;
+        }
+
+        Ok(records)
+    }
+}
+
+// Snappy has no levels. Lz4 frame uses independent 64 KB blocks without content checksum, same as java client writes it
+fn compress_with_level(compression: Compression, level: i32, records_bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level as u32));
+            encoder.write_all(records_bytes)?;
+
+            Ok(encoder.finish()?)
+        },
+        Compression::Lz4 => {
+            let mut encoder = 
+                lz4::EncoderBuilder::new()
+                    .level(level as u32)
+                    .block_size(lz4::BlockSize::Max64KB)
+                    .block_mode(lz4::BlockMode::Independent)
+                    .checksum(lz4::ContentChecksum::NoChecksum)
+                    .build(Vec::new())?;
+            encoder.write_all(records_bytes)?;
+            let (compressed, result) = encoder.finish();
+            result?;
+
+            Ok(compressed)
+        },
+        Compression::Zstd => Ok(zstd::bulk::compress(records_bytes, level)?),
+        Compression::None | Compression::Snappy => Err(Box::new(KafkaCallerError::new("Compression level is supported only by gzip, lz4 and zstd"))),
+    }
+}
+
+fn encode_record(buf: &mut BytesMut, record: &Record, base_offset: i64, base_timestamp: i64) {
+    let mut record_bytes = BytesMut::new();
+
+    // record attributes are unused
+    record_bytes.put_i8(0);
+    put_varint(&mut record_bytes, record.timestamp - base_timestamp);
+    put_varint(&mut record_bytes, record.offset - base_offset);
+    put_nullable_bytes(&mut record_bytes, record.key.as_deref());
+    put_nullable_bytes(&mut record_bytes, record.value.as_deref());
+
+    put_varint(&mut record_bytes, record.headers.len() as i64);
+    for header in record.headers.iter() {
+        put_nullable_bytes(&mut record_bytes, Some(header.key.as_bytes()));
+        put_nullable_bytes(&mut record_bytes, header.value.as_deref());
+    }
+
+    put_varint(buf, record_bytes.len() as i64);
+    buf.put_slice(&record_bytes);
+}
+
+fn decode_batch(mut batch: Bytes, records: &mut Vec<Record>) -> Result<(), Box<dyn Error>> {
+    // legacy message sets (magic 0 and 1) are written only by brokers with message format older than 0.11,
+    // or kept in old log segments, they are not decoded
+    if let Some(magic) = batch.get(MAGIC_OFFSET).filter(|magic| **magic < 2) {
+        return Err(Box::new(KafkaCallerError::new(&format!(
+            "Legacy message set with magic '{}' at offset '{}' is not supported, only record batches (magic 2) are",
+            magic,
+            i64::from_be_bytes(batch[..8].try_into()?)
+        ))));
+    }
+
+    if batch.remaining() < RECORD_BATCH_OVERHEAD {
+        return Err(Box::new(KafkaCallerError::new(&format!("Record batch of {} bytes is shorter than its header", batch.remaining()))));
+    }
+
+    let base_offset = batch.get_i64();
+    let _batch_length = batch.get_i32();
+    let partition_leader_epoch = batch.get_i32();
+    let magic = batch.get_i8();
+
+    if magic != 2 {
+        return Err(Box::new(KafkaCallerError::new(&format!("Record batch with magic '{}' is not supported, only magic 2 is", magic))));
+    }
+
+    let crc = batch.get_u32();
+    if crc != crc32c::crc32c(&batch) {
+        return Err(Box::new(KafkaCallerError::new(&format!("Record batch at offset '{}' is corrupt, its crc does not match", base_offset))));
+    }
+
+    let attributes = batch.get_i16();
+    let _last_offset_delta = batch.get_i32();
+    let base_timestamp = batch.get_i64();
+    let max_timestamp = batch.get_i64();
+    let producer_id = batch.get_i64();
+    let producer_epoch = batch.get_i16();
+    let base_sequence = batch.get_i32();
+    let record_count = batch.get_i32();
+
+    let timestamp_type =
+        if attributes & LOG_APPEND_TIME_FLAG == 0 {
+            TimestampType::CreateTime
+        } else {
+            TimestampType::LogAppendTime
+        };
+
+    let mut records_bytes =
+        match attributes & COMPRESSION_MASK {
+            0 => batch,
+            1 => Gzip::decompress(&mut batch, |decompressed| Ok(decompressed.copy_to_bytes(decompressed.remaining())))?,
+            2 => Snappy::decompress(&mut batch, |decompressed| Ok(decompressed.copy_to_bytes(decompressed.remaining())))?,
+            3 => Lz4::decompress(&mut batch, |decompressed| Ok(decompressed.copy_to_bytes(decompressed.remaining())))?,
+            4 => Zstd::decompress(&mut batch, |decompressed| Ok(decompressed.copy_to_bytes(decompressed.remaining())))?,
+            codec => return Err(Box::new(KafkaCallerError::new(&format!("Record batch at offset '{}' has unknown compression codec '{}'", base_offset, codec)))),
+        };
+
+    for index in 0..record_count.max(0) {
+        let record_length = get_varint(&mut records_bytes)?;
+        let mut record_bytes = take_bytes(&mut records_bytes, record_length)?;
+
+        let _attributes = take_bytes(&mut record_bytes, 1)?;
+        let timestamp_delta = get_varint(&mut record_bytes)?;
+        let offset_delta = get_varint(&mut record_bytes)?;
+        let key = get_nullable_bytes(&mut record_bytes)?;
+        let value = get_nullable_bytes(&mut record_bytes)?;
+
+        let header_count = get_varint(&mut record_bytes)?;
+        let mut headers = Headers::default();
+        for _ in 0..header_count.max(0) {
+            let header_key =
+                get_nullable_bytes(&mut record_bytes)?
+                    .ok_or(KafkaCallerError::new(&format!("Record at offset '{}' has header with null key", base_offset + offset_delta)))?;
+            let header_value = get_nullable_bytes(&mut record_bytes)?;
+
+            headers.add(&String::from_utf8_lossy(&header_key), header_value);
+        }
+
+        records.push(
+            Record {
+                transactional: attributes & TRANSACTIONAL_FLAG != 0,
+                control: attributes & CONTROL_FLAG != 0,
+                partition_leader_epoch,
+                producer_id,
+                producer_epoch,
+                timestamp_type,
+                offset: base_offset + offset_delta,
+                sequence: if base_sequence == -1 { -1 } else { increment_sequence(base_sequence, index) },
+                // with log append time, broker sets only the timestamp of the batch
+                timestamp:
+                    match timestamp_type {
+                        TimestampType::CreateTime => base_timestamp + timestamp_delta,
+                        TimestampType::LogAppendTime => max_timestamp,
+                    },
+                key,
+                value,
+                headers,
+            }
+        );
+    }
+
+    Ok(())
+}
+
+// zigzag encoded variable length integer, same as varint and varlong of protocol buffers
+fn put_varint(buf: &mut BytesMut, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+
+    while zigzag >= 0x80 {
+        buf.put_u8((zigzag as u8 & 0x7F) | 0x80);
+        zigzag >>= 7;
+    }
+
+    buf.put_u8(zigzag as u8);
+}
+
+fn get_varint(buf: &mut Bytes) -> Result<i64, Box<dyn Error>> {
+    let mut zigzag = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return Err(Box::new(KafkaCallerError::new("Record ends in the middle of a varint")));
+        }
+
+        let byte = buf.get_u8();
+        zigzag |= ((byte & 0x7F) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+        }
+    }
+
+    Err(Box::new(KafkaCallerError::new("Record has varint longer than 10 bytes")))
+}
+
+pub(crate) fn varint_size(value: i64) -> usize {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    let mut size = 1;
+
+    while zigzag >= 0x80 {
+        zigzag >>= 7;
+        size += 1;
+    }
+
+    size
+}
+
+// length -1 marks null
+fn put_nullable_bytes(buf: &mut BytesMut, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            put_varint(buf, bytes.len() as i64);
+            buf.put_slice(bytes);
+        },
+        None => put_varint(buf, -1),
+    }
+}
+
+pub(crate) fn nullable_bytes_size(bytes: Option<&[u8]>) -> usize {
+    match bytes {
+        Some(bytes) => varint_size(bytes.len() as i64) + bytes.len(),
+        None => varint_size(-1),
+    }
+}
+
+fn get_nullable_bytes(buf: &mut Bytes) -> Result<Option<Bytes>, Box<dyn Error>> {
+    let length = get_varint(buf)?;
+
+    if length < 0 {
+        return Ok(None);
+    }
+
+    take_bytes(buf, length).map(Some)
+}
+
+fn take_bytes(buf: &mut Bytes, length: i64) -> Result<Bytes, Box<dyn Error>> {
+    if length < 0 || length as usize > buf.remaining() {
+        return Err(Box::new(KafkaCallerError::new(&format!("Record field of {} bytes does not fit into remaining {} bytes", length, buf.remaining()))));
+    }
+
+    Ok(buf.split_to(length as usize))
+}
@@ -1,25 +1,205 @@
-use std::collections::{HashMap, HashSet};
+use std::error::Error;
 
 use bytes::Bytes;
-use kafka_protocol::records::{Record, TimestampType};
+use indexmap::IndexMap;
 
-use crate::utils::to_kafka_str;
+use crate::errors::RecordDeserializationError;
+use crate::io::record_batch::{Record, nullable_bytes_size, varint_size};
+use crate::serialization::{Deserializer, Serializer};
 
+// Header of a record. Key is a string in Kafka protocol, value is raw bytes and may be null.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub key: String,
+    pub value: Option<Bytes>,
+}
+
+impl Header {
+    pub fn new(key: &str, value: Option<Bytes>) -> Self {
+        Self {
+            key: String::from(key),
+            value,
+        }
+    }
+
+    // None when value is null or not valid UTF-8
+    pub fn value_str(&self) -> Option<&str> {
+        self.value
+            .as_ref()
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+}
+
+// Headers in the order they were added, the same key may be present more than once (same as Headers of java client)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    headers: Vec<Header>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<Bytes>) {
+        self.headers.push(Header::new(key, value));
+    }
+
+    pub fn add_bytes(&mut self, key: &str, value: &[u8]) {
+        self.add(key, Some(Bytes::copy_from_slice(value)));
+    }
+
+    pub fn add_str(&mut self, key: &str, value: &str) {
+        self.add_bytes(key, value.as_bytes());
+    }
+
+    // removes all headers with the key
+    pub fn remove(&mut self, key: &str) {
+        self.headers.retain(|header| header.key != key);
+    }
+
+    // header with the key which was added last
+    pub fn last_header(&self, key: &str) -> Option<&Header> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|header| header.key == key)
+    }
+
+    // all headers with the key in the order they were added
+    pub fn headers<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Header> {
+        self.headers
+            .iter()
+            .filter(move |header| header.key == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Header> {
+        self.headers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a Header;
+    type IntoIter = std::slice::Iter<'a, Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.headers.iter()
+    }
+}
+
+impl IntoIterator for Headers {
+    type Item = Header;
+    type IntoIter = std::vec::IntoIter<Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.headers.into_iter()
+    }
+}
+
+impl FromIterator<Header> for Headers {
+    fn from_iter<I: IntoIterator<Item = Header>>(iter: I) -> Self {
+        Self {
+            headers: iter.into_iter().collect(),
+        }
+    }
+}
+
+// record to produce, key and value are raw bytes unless producer was created with serializers
 #[derive(Debug, Clone)]
-pub struct PutRecord {
+pub struct PutRecord<K = Bytes, V = Bytes> {
     pub topic: String,
-    pub key: Option<Bytes>,
-    pub value: Option<Bytes>,
-    pub headers: HashMap<String, Option<Bytes>>,
+    // explicit target partition, if None partition is chosen from the key
+    pub partition: Option<i32>,
+    // create time in milliseconds since epoch, if None time of send is used,
+    // topics with "message.timestamp.type" LogAppendTime replace it with broker time
+    pub timestamp: Option<i64>,
+    pub key: Option<K>,
+    pub value: Option<V>,
+    pub headers: Headers,
+}
+
+impl<K, V> PutRecord<K, V> {
+    pub fn with_key_value(topic: &str, key: Option<K>, value: Option<V>) -> Self {
+        Self {
+            topic: String::from(topic),
+            partition: None,
+            timestamp: None,
+            key,
+            value,
+            headers: Headers::default(),
+        }
+    }
+
+    // record without key, partitioned by the sticky partitioner unless partition is set
+    pub fn with_value(topic: &str, value: V) -> Self {
+        Self::with_key_value(topic, None, Some(value))
+    }
+
+    // record with null value, which deletes the key from a compacted topic
+    pub fn tombstone(topic: &str, key: K) -> Self {
+        Self::with_key_value(topic, Some(key), None)
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_none()
+    }
+
+    pub fn set_partition(&mut self, partition: i32) {
+        self.partition = Some(partition);
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = Some(timestamp);
+    }
+
+    pub fn add_header_str(&mut self, key: &str, value: &str) {
+        self.headers.add_str(key, value);
+    }
+
+    pub fn add_header_bytes(&mut self, key: &str, value: &[u8]) {
+        self.headers.add_bytes(key, value);
+    }
+
+    // null key or value stays None and is not passed to serializer
+    pub(in super::super) fn serialize(&self, key_serializer: &dyn Serializer<K>, value_serializer: &dyn Serializer<V>) -> Result<PutRecord, Box<dyn Error>> {
+        Ok(
+            PutRecord {
+                topic: self.topic.clone(),
+                partition: self.partition,
+                timestamp: self.timestamp,
+                key: 
+                    self.key
+                        .as_ref()
+                        .map(|key| key_serializer.serialize(&self.topic, key))
+                        .transpose()?,
+                value: 
+                    self.value
+                        .as_ref()
+                        .map(|value| value_serializer.serialize(&self.topic, value))
+                        .transpose()?,
+                headers: self.headers.clone(),
+            }
+        )
+    }
 }
 
 impl PutRecord {
     fn new(topic: &str) -> Self {
         Self {
             topic: String::from(topic),
+            partition: None,
+            timestamp: None,
             key: None,
             value: None,
-            headers: HashMap::default(),       
+            headers: Headers::default(),
         }
     }
 
@@ -51,7 +231,19 @@ impl PutRecord {
     }
 
     pub fn add_header_with_str_key(&mut self, key: &str, value: &[u8]) {
-        self.headers.insert(String::from(key), Some(Bytes::copy_from_slice(value)));
+        self.headers.add_bytes(key, value);
+    }
+
+    // size of key, value and headers as encoded, with their lengths and header count, used for batching,
+    // without record overhead (MAX_RECORD_OVERHEAD) and record batch overhead
+    pub(crate) fn size_in_bytes(&self) -> usize {
+        nullable_bytes_size(self.key.as_deref()) +
+        nullable_bytes_size(self.value.as_deref()) +
+        varint_size(self.headers.len() as i64) +
+        self.headers
+            .iter()
+            .map(|header| nullable_bytes_size(Some(header.key.as_bytes())) + nullable_bytes_size(header.value.as_deref()))
+            .sum::<usize>()
     }
 }
 
@@ -63,30 +255,241 @@ impl From<&PutRecord> for Record {
             partition_leader_epoch: -1, 
             producer_id: -1, 
             producer_epoch: -1, 
-            timestamp_type: TimestampType::Creation, 
+            timestamp_type: TimestampType::CreateTime, 
             offset: -1,
             sequence: -1, 
-            timestamp: -1,
+            timestamp: put_record.timestamp.unwrap_or(-1),
             key: put_record.key.clone(), 
             value: put_record.value.clone(), 
-            headers: 
-                put_record.headers
-                    .iter()
-                    .map(|(key, value)|
-                        (to_kafka_str(key), value.clone())
-                    )   
-                    .collect(),
+            headers: put_record.headers.clone(),
         }
     }
 }
 
-pub(in super::super) fn extract_topics(records: &[PutRecord]) -> Vec<String> {
-    records
-        .iter()
-        .map(|put_record|
-            put_record.topic.clone()
-        )
-        .collect::<HashSet<String>>()
-        .into_iter()
-        .collect()
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicPartition {
+    pub topic: String,
+    pub partition: i32,
+}
+
+impl TopicPartition {
+    pub fn new(topic: &str, partition: i32) -> Self {
+        Self {
+            topic: String::from(topic),
+            partition,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampType {
+    // timestamp set by producer
+    CreateTime,
+    // timestamp set by broker when appending the record to log
+    LogAppendTime,
+}
+
+// delivery result of a produced record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordMetadata {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub timestamp: i64,
+    pub timestamp_type: TimestampType,
+}
+
+// record returned by consumer, key and value are raw bytes unless consumer was created with deserializers
+#[derive(Debug, Clone)]
+pub struct ConsumerRecord<K = Bytes, V = Bytes> {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub timestamp: i64,
+    pub timestamp_type: TimestampType,
+    // None when the record was produced without partition leader epoch
+    pub leader_epoch: Option<i32>,
+    pub key: Option<K>,
+    pub value: Option<V>,
+    pub headers: Headers,
+}
+
+impl<K, V> ConsumerRecord<K, V> {
+    // null value, as opposed to empty one, marks deletion of the key in a compacted topic
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_none()
+    }
+}
+
+impl ConsumerRecord {
+    pub(in super::super) fn from_record(topic: &str, partition: i32, record: Record) -> Self {
+        Self {
+            topic: String::from(topic),
+            partition,
+            offset: record.offset,
+            timestamp: record.timestamp,
+            timestamp_type: record.timestamp_type,
+            leader_epoch: (record.partition_leader_epoch >= 0).then_some(record.partition_leader_epoch),
+            key: record.key,
+            value: record.value,
+            headers: record.headers,
+        }
+    }
+
+    // null key or value stays None and is not passed to deserializer
+    pub(in super::super) fn deserialize<K, V>(self, key_deserializer: &dyn Deserializer<K>, value_deserializer: &dyn Deserializer<V>) -> Result<ConsumerRecord<K, V>, RecordDeserializationError> {
+        let key = 
+            self.key
+                .as_ref()
+                .map(|key| key_deserializer.deserialize(&self.topic, key))
+                .transpose();
+        let value = 
+            self.value
+                .as_ref()
+                .map(|value| value_deserializer.deserialize(&self.topic, value))
+                .transpose();
+
+        match (key, value) {
+            (Ok(key), Ok(value)) => 
+                Ok(
+                    ConsumerRecord {
+                        topic: self.topic,
+                        partition: self.partition,
+                        offset: self.offset,
+                        timestamp: self.timestamp,
+                        timestamp_type: self.timestamp_type,
+                        leader_epoch: self.leader_epoch,
+                        key,
+                        value,
+                        headers: self.headers,
+                    }
+                ),
+            (Err(error), _) | (_, Err(error)) => 
+                Err(
+                    RecordDeserializationError {
+                        topic: self.topic,
+                        partition: self.partition,
+                        offset: self.offset,
+                        key: self.key,
+                        value: self.value,
+                        message: error.to_string(),
+                    }
+                ),
+        }
+    }
+}
+
+// Records returned by one poll, grouped by partition in the order they were fetched.
+// Records which could not be deserialized are not part of the partition records, they are reported separately.
+#[derive(Debug, Clone)]
+pub struct ConsumerRecords<K = Bytes, V = Bytes> {
+    records: IndexMap<TopicPartition, Vec<ConsumerRecord<K, V>>>,
+    deserialization_errors: Vec<RecordDeserializationError>,
+}
+
+impl<K, V> Default for ConsumerRecords<K, V> {
+    fn default() -> Self {
+        Self {
+            records: IndexMap::new(),
+            deserialization_errors: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> ConsumerRecords<K, V> {
+    pub fn is_empty(&self) -> bool {
+        self.deserialization_errors.is_empty() && self.records.values().all(|partition_records| partition_records.is_empty())
+    }
+
+    pub fn count(&self) -> usize {
+        self.records.values().map(|partition_records| partition_records.len()).sum()
+    }
+
+    pub fn partitions(&self) -> impl Iterator<Item = &TopicPartition> {
+        self.records.keys()
+    }
+
+    pub fn records(&self, topic_partition: &TopicPartition) -> &[ConsumerRecord<K, V>] {
+        self.records
+            .get(topic_partition)
+            .map(|partition_records| partition_records.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn records_for_topic<'a>(&'a self, topic: &'a str) -> impl Iterator<Item = &'a ConsumerRecord<K, V>> {
+        self.records
+            .iter()
+            .filter(move |(topic_partition, _)| topic_partition.topic == topic)
+            .flat_map(|(_, partition_records)| partition_records.iter())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConsumerRecord<K, V>> {
+        self.records.values().flatten()
+    }
+
+    pub fn deserialization_errors(&self) -> &[RecordDeserializationError] {
+        &self.deserialization_errors
+    }
+
+    pub(in super::super) fn add(&mut self, topic_partition: TopicPartition, mut partition_records: Vec<ConsumerRecord<K, V>>) {
+        if partition_records.is_empty() {
+            return;
+        }
+
+        self.records
+            .entry(topic_partition)
+            .or_default()
+            .append(&mut partition_records);
+    }
+
+    pub(in super::super) fn push(&mut self, record: ConsumerRecord<K, V>) {
+        self.records
+            .entry(TopicPartition::new(&record.topic, record.partition))
+            .or_default()
+            .push(record);
+    }
+
+    pub(in super::super) fn push_error(&mut self, deserialization_error: RecordDeserializationError) {
+        self.deserialization_errors.push(deserialization_error);
+    }
+
+    pub(in super::super) fn append(&mut self, other: ConsumerRecords<K, V>) {
+        other.records
+            .into_iter()
+            .for_each(|(topic_partition, partition_records)| self.add(topic_partition, partition_records));
+        self.deserialization_errors.extend(other.deserialization_errors);
+    }
+}
+
+impl<K, V> ConsumerRecords<K, V> {
+    // records together with deserialization errors, ordered by offset within each partition
+    pub(in super::super) fn into_results(self) -> Vec<Result<ConsumerRecord<K, V>, RecordDeserializationError>> {
+        let mut results: Vec<Result<ConsumerRecord<K, V>, RecordDeserializationError>> = 
+            self.records
+                .into_values()
+                .flatten()
+                .map(Ok)
+                .chain(self.deserialization_errors.into_iter().map(Err))
+                .collect();
+
+        results.sort_by(|first, second| result_position(first).cmp(&result_position(second)));
+
+        results
+    }
+}
+
+fn result_position<K, V>(result: &Result<ConsumerRecord<K, V>, RecordDeserializationError>) -> (&str, i32, i64) {
+    match result {
+        Ok(record) => (&record.topic, record.partition, record.offset),
+        Err(deserialization_error) => (&deserialization_error.topic, deserialization_error.partition, deserialization_error.offset),
+    }
+}
+
+impl<K, V> IntoIterator for ConsumerRecords<K, V> {
+    type Item = ConsumerRecord<K, V>;
+    type IntoIter = std::iter::Flatten<indexmap::map::IntoValues<TopicPartition, Vec<ConsumerRecord<K, V>>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.into_values().flatten()
+    }
 }
\ No newline at end of file
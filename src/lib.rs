@@ -1,59 +1,413 @@
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::fmt::Debug;
 use std::net::TcpStream;
-use std::thread::{self};
-use std::time::Duration;
-use errors::KafkaCallerError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use bytes::{Bytes, BytesMut};
 use io::messages::{CreateSerDe, SerDe, CreateRequest, ProcessResponse};
-use io::records::{PutRecord, extract_topics};
-use kafka_protocol::messages::{ApiKey, ApiVersionsRequest, ApiVersionsResponse, MetadataRequest, MetadataResponse, FindCoordinatorRequest, FindCoordinatorResponse, JoinGroupRequest, JoinGroupResponse, FetchRequest, FetchResponse, SyncGroupRequest, SyncGroupResponse, OffsetFetchRequest, OffsetFetchResponse, ListOffsetsRequest, ListOffsetsResponse, OffsetCommitRequest, OffsetCommitResponse, LeaveGroupRequest, LeaveGroupResponse, HeartbeatRequest, HeartbeatResponse, InitProducerIdRequest, InitProducerIdResponse, ProduceRequest, ProduceResponse};
+use io::accumulator::{AcknowledgementHook, RecordAccumulator, SharedAccumulator, TransactionCommand, MAX_RECORD_OVERHEAD, RECORD_BATCH_OVERHEAD};
+use partitioner::assign_partitions;
+use kafka_protocol::messages::{ApiKey, ApiVersionsRequest, ApiVersionsResponse, MetadataRequest, MetadataResponse, FindCoordinatorRequest, FindCoordinatorResponse, JoinGroupRequest, JoinGroupResponse, FetchRequest, FetchResponse, SyncGroupRequest, SyncGroupResponse, OffsetFetchRequest, OffsetFetchResponse, ListOffsetsRequest, ListOffsetsResponse, OffsetCommitRequest, OffsetCommitResponse, LeaveGroupRequest, LeaveGroupResponse, OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse};
 use kafka_protocol::protocol::{Decodable, Encodable, Message, HeaderVersion};
-use kafka_protocol::records::Record;
+use kafka_protocol::records::Compression;
 use crate::io::call_state::CallState;
 use crate::io::IO;
 use crate::io::messages::fetch::ProcessFetchResponse;
+use crate::sender::Sender;
+use crate::heartbeat::{Heartbeat, HeartbeatState, MembershipLoss, SharedHeartbeat};
 
 mod io;
 mod errors;
 mod utils;
+mod serialization;
+mod partitioner;
+mod interceptor;
+mod sender;
+mod heartbeat;
+#[cfg(feature = "async")]
+mod stream;
 mod tests;
 
+pub use io::records::{PutRecord, ConsumerRecord, ConsumerRecords, TopicPartition, TimestampType, RecordMetadata, Header, Headers};
+pub use errors::{KafkaCallerError, RecordDeserializationError};
+pub use io::accumulator::SendHandle;
+#[cfg(feature = "async")]
+pub use stream::ConsumerStream;
+pub use serialization::{Deserializer, BytesDeserializer, StringDeserializer, I32Deserializer, I64Deserializer, UuidDeserializer, JsonDeserializer};
+pub use partitioner::{Partitioner, DefaultPartitioner, StickyPartitioner, RoundRobinPartitioner};
+pub use interceptor::{ProducerInterceptor, ConsumerInterceptor};
+pub use serialization::{Serializer, BytesSerializer, StringSerializer, I32Serializer, I64Serializer, UuidSerializer, JsonSerializer};
+
 #[derive(Debug, Clone)]
 pub enum Configuration {
     ConsumerConfiguration {
         broker_address: String,
         client_id: String,
         group_id: String,
+        auto_offset_reset: OffsetResetPolicy,
+        // rack of the consumer, sent with fetch so the leader can redirect it to a follower in the same rack, same as "client.rack" of java client
+        client_rack: Option<String>,
+        isolation_level: IsolationLevel,
+        // maximum number of records returned by one poll, the rest is kept for following polls
+        max_poll_records: usize,
+        // records returned by poll are committed by the next poll and by close, same as "enable.auto.commit" of java client
+        // (which is on by default there). Without it application commits them by commit_sync, or sends consumed_offsets
+        // to transaction of a producer
+        enable_auto_commit: bool,
+        // when application does not poll within this interval, consumer leaves the group, also used as rebalance timeout
+        max_poll_interval_ms: i32,
+        // coordinator removes the member when it gets no heartbeat within this time, heartbeats are sent by background thread
+        // every third of it, same as "session.timeout.ms" of java client
+        session_timeout_ms: i32,
+        fetch_min_bytes: i32,
+        fetch_max_bytes: i32,
+        fetch_max_wait_ms: i32,
+        max_partition_fetch_bytes: i32,
     },
     ProducerConfiguration {
         broker_address: String,
         client_id: String,
+        // records of a partition are collected into batches of up to this many bytes
+        batch_size: usize,
+        // how long a batch waits for more records before it is sent even when not full
+        linger_ms: u64,
+        // total bytes of records waiting to be sent, send blocks when exceeded
+        buffer_memory: usize,
+        acks: Acks,
+        // how long producer waits for broker to answer a request, also sent to broker as produce timeout
+        request_timeout_ms: i32,
+        // upper bound on time from send until record is delivered or failed, including retries
+        delivery_timeout_ms: u64,
+        // records are written exactly once per partition even when retried, requires acks All
+        enable_idempotence: bool,
+        // enables transactions, requires idempotence, same as "transactional.id" of java client
+        transactional_id: Option<String>,
+        // coordinator aborts transaction which is not completed within this time
+        transaction_timeout_ms: i32,
+        // codec of produced record batches, same as "compression.type" of java client
+        compression_type: CompressionType,
+        // level of gzip (0-9), lz4 (0-16) or zstd codec, default level of the codec when not set, same as "compression.<codec>.level"
+        // of java client
+        compression_level: Option<i32>,
+        // upper bound on size of one produce request, records which would not fit even alone are rejected by send
+        max_request_size: usize,
     }
 }
 
 impl Configuration {
+    pub fn broker_address(&self) -> String {
+        match self {
+            Configuration::ProducerConfiguration { broker_address, .. } => broker_address.clone(),
+            Configuration::ConsumerConfiguration { broker_address, .. } => broker_address.clone()
+        }
+    }
+
     pub fn client_id(&self) -> String {
         match self {
-            Configuration::ProducerConfiguration { broker_address: _, client_id } => client_id.clone(),
-            Configuration::ConsumerConfiguration { broker_address: _, client_id, group_id: _ } => client_id.clone()
+            Configuration::ProducerConfiguration { client_id, .. } => client_id.clone(),
+            Configuration::ConsumerConfiguration { client_id, .. } => client_id.clone()
         }
     }
 
     pub fn group_id(&self) -> Result<String, KafkaCallerError> {
         match self {
-            Configuration::ConsumerConfiguration { broker_address: _, client_id: _, group_id } => Ok(group_id.clone()),
+            Configuration::ConsumerConfiguration { group_id, .. } => Ok(group_id.clone()),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn auto_offset_reset(&self) -> Result<OffsetResetPolicy, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { auto_offset_reset, .. } => Ok(*auto_offset_reset),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn isolation_level(&self) -> Result<IsolationLevel, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { isolation_level, .. } => Ok(*isolation_level),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn client_rack(&self) -> Result<Option<String>, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { client_rack, .. } => Ok(client_rack.clone()),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn max_poll_records(&self) -> Result<usize, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { max_poll_records, .. } => Ok(*max_poll_records),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn enable_auto_commit(&self) -> Result<bool, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { enable_auto_commit, .. } => Ok(*enable_auto_commit),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn max_poll_interval_ms(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { max_poll_interval_ms, .. } => Ok(*max_poll_interval_ms),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn session_timeout_ms(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { session_timeout_ms, .. } => Ok(*session_timeout_ms),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn fetch_min_bytes(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { fetch_min_bytes, .. } => Ok(*fetch_min_bytes),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn fetch_max_bytes(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { fetch_max_bytes, .. } => Ok(*fetch_max_bytes),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn fetch_max_wait_ms(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { fetch_max_wait_ms, .. } => Ok(*fetch_max_wait_ms),
             _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
         }
     }
+
+    pub fn max_partition_fetch_bytes(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ConsumerConfiguration { max_partition_fetch_bytes, .. } => Ok(*max_partition_fetch_bytes),
+            _ => Err(KafkaCallerError::new("Not supported for producer configuration"))
+        }
+    }
+
+    pub fn batch_size(&self) -> Result<usize, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { batch_size, .. } => Ok(*batch_size),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn linger_ms(&self) -> Result<u64, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { linger_ms, .. } => Ok(*linger_ms),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn buffer_memory(&self) -> Result<usize, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { buffer_memory, .. } => Ok(*buffer_memory),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn acks(&self) -> Result<Acks, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { acks, .. } => Ok(*acks),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn request_timeout_ms(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { request_timeout_ms, .. } => Ok(*request_timeout_ms),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn delivery_timeout_ms(&self) -> Result<u64, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { delivery_timeout_ms, .. } => Ok(*delivery_timeout_ms),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn enable_idempotence(&self) -> Result<bool, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { enable_idempotence, .. } => Ok(*enable_idempotence),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn transactional_id(&self) -> Result<Option<String>, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { transactional_id, .. } => Ok(transactional_id.clone()),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn transaction_timeout_ms(&self) -> Result<i32, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { transaction_timeout_ms, .. } => Ok(*transaction_timeout_ms),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn compression_level(&self) -> Result<Option<i32>, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { compression_level, .. } => Ok(*compression_level),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn compression_type(&self) -> Result<CompressionType, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { compression_type, .. } => Ok(*compression_type),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
+
+    pub fn max_request_size(&self) -> Result<usize, KafkaCallerError> {
+        match self {
+            Configuration::ProducerConfiguration { max_request_size, .. } => Ok(*max_request_size),
+            _ => Err(KafkaCallerError::new("Not supported for consumer configuration"))
+        }
+    }
 }
 
-pub struct Consumer {
+// what to do when there is no committed offset or the broker reports the fetched offset as out of range,
+// same as "auto.offset.reset" of java client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetResetPolicy {
+    Earliest,
+    Latest,
+    // fail the poll instead of moving the position
+    None,
+}
+
+// which records are visible to consumer, same as "isolation.level" of java client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    // only records of committed transactions up to last stable offset are returned
+    ReadCommitted,
+}
+
+impl From<IsolationLevel> for i8 {
+    fn from(isolation_level: IsolationLevel) -> Self {
+        match isolation_level {
+            IsolationLevel::ReadUncommitted => 0,
+            IsolationLevel::ReadCommitted => 1,
+        }
+    }
+}
+
+// acknowledgements producer waits for, same as "acks" of java client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acks {
+    // fire and forget, broker does not send any response and records have no offset
+    None,
+    // leader wrote the records to its log
+    Leader,
+    // all in-sync replicas have the records
+    All,
+}
+
+impl From<Acks> for i16 {
+    fn from(acks: Acks) -> Self {
+        match acks {
+            Acks::None => 0,
+            Acks::Leader => 1,
+            Acks::All => -1,
+        }
+    }
+}
+
+// codec of produced record batches, consumer decodes all of them regardless of this setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionType {
+    fn check_level(&self, level: i32) -> Result<(), KafkaCallerError> {
+        let levels =
+            match self {
+                CompressionType::Gzip => 0..=9,
+                CompressionType::Lz4 => 0..=16,
+                CompressionType::Zstd => zstd::compression_level_range(),
+                CompressionType::None | CompressionType::Snappy => 
+                    return Err(KafkaCallerError::new(&format!("Compression level is not supported by compression type {:?}", self))),
+            };
+
+        if !levels.contains(&level) {
+            return Err(KafkaCallerError::new(&format!("Compression level {} is out of range {:?} of compression type {:?}", level, levels, self)));
+        }
+
+        Ok(())
+    }
+}
+
+impl From<CompressionType> for Compression {
+    fn from(compression_type: CompressionType) -> Self {
+        match compression_type {
+            CompressionType::None => Compression::None,
+            CompressionType::Gzip => Compression::Gzip,
+            CompressionType::Snappy => Compression::Snappy,
+            CompressionType::Lz4 => Compression::Lz4,
+            CompressionType::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerGroupMetadata {
+    pub group_id: String,
+    pub generation_id: i32,
+    pub member_id: String,
+    pub group_instance_id: Option<String>,
+}
+
+pub struct Consumer<K = Bytes, V = Bytes> {
     state: CallState,
     io: IO,
+    fetch_connections: HashMap<i32, IO>,
+    // fetched records which were not yet returned because of max poll records
+    record_buffer: VecDeque<ConsumerRecord>,
+    // membership shared with heartbeat thread, which also watches max poll interval
+    heartbeat: SharedHeartbeat,
+    heartbeat_thread: Option<JoinHandle<()>>,
+    key_deserializer: Box<dyn Deserializer<K> + Send>,
+    value_deserializer: Box<dyn Deserializer<V> + Send>,
+    // records of last poll which were not yet returned by iterator
+    iterated_records: VecDeque<Result<ConsumerRecord<K, V>, RecordDeserializationError>>,
+    interceptors: Vec<Box<dyn ConsumerInterceptor<K, V> + Send>>,
 }
 
 impl Consumer {
+    // consumer returning raw key and value bytes
     pub fn new(configuration: &Configuration) -> Result<Self, Box<dyn Error>> {
+        Self::with_deserializers(configuration, BytesDeserializer, BytesDeserializer)
+    }
+}
+
+impl<K, V> Consumer<K, V> {
+    pub fn with_deserializers(
+        configuration: &Configuration, 
+        key_deserializer: impl Deserializer<K> + Send + 'static, 
+        value_deserializer: impl Deserializer<V> + Send + 'static
+    ) -> Result<Self, Box<dyn Error>> {
         if let Configuration::ConsumerConfiguration{broker_address, ..} = configuration {
             let tcp_stream = TcpStream::connect(broker_address)?;
 
@@ -61,6 +415,14 @@ impl Consumer {
                 Self {
                     state: CallState::new(configuration)?,
                     io: IO::from(tcp_stream),
+                    fetch_connections: HashMap::new(),
+                    record_buffer: VecDeque::new(),
+                    heartbeat: Arc::new((Mutex::new(HeartbeatState::new()), Condvar::new())),
+                    heartbeat_thread: None,
+                    key_deserializer: Box::new(key_deserializer),
+                    value_deserializer: Box::new(value_deserializer),
+                    iterated_records: VecDeque::new(),
+                    interceptors: Vec::new(),
                 }
             )
         } else {
@@ -68,6 +430,11 @@ impl Consumer {
         }
     }
 
+    // interceptors are called in the order they were added
+    pub fn add_interceptor(&mut self, interceptor: impl ConsumerInterceptor<K, V> + Send + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
     pub fn subscribe(&mut self, topics: Vec<&str>) {
         self.state.connected_topics =
             topics
@@ -83,27 +450,253 @@ impl Consumer {
     // polls even on subsequent calls, however these are probably not entirely correct, as it no longer fully matches java client.
     // Theoretically, implementation should handle poll from multiple topics and multiple partitions(on one broker only), but that remains untested.
     // (there are more calls by java client in practice, especially several ApiVersions calls, but this is enough to correctly poll entries)
-    pub fn first_poll(&mut self) -> Result<Vec<Record>, Box<dyn Error>> {
+    // Per-partition fetch errors do not fail the whole poll - records of other partitions are still returned and the error
+    // is raised on the next call instead (like java client does).
+    pub fn first_poll(&mut self) -> Result<ConsumerRecords<K, V>, Box<dyn Error>> {
+        if let Some(fetch_error) = self.state.pending_fetch_error.take() {
+            return Err(Box::new(fetch_error));
+        }
+
+        self.join_group()?;
+        // this also resets positions of partitions which got OFFSET_OUT_OF_RANGE in previous fetch
+        self.list_offsets()?;
+        let fetched = self.do_call_fetch()?;
+        self.record_buffer.extend(fetched);
+        let result = self.drain_record_buffer()?;
+        self.auto_commit_consumed_offsets()?;
+        // records over max poll records are dropped from buffer on leave and fetched again by next poll
+        self.leave_group()?;
+ 
+        let records = self.deserialize_records(result);
+
+        Ok(self.intercept_consumed(records))
+    }
+
+    // Poll which stays in the group between calls. Records returned by previous poll are committed at the start of the next one
+    // (unless auto commit is disabled), and fetch is performed only when all previously fetched records were returned.
+    // Heartbeats are sent by background thread. When application does not call poll within max poll interval, the thread leaves
+    // the group (without committing last returned records), consumer finds out on the next poll and joins it again.
+    pub fn poll(&mut self) -> Result<ConsumerRecords<K, V>, Box<dyn Error>> {
+        self.poll_started();
+
+        if let Some(fetch_error) = self.state.pending_fetch_error.take() {
+            return Err(Box::new(fetch_error));
+        }
+
+        if self.is_group_member() {
+            self.auto_commit_consumed_offsets()?;
+
+            // commit failed due to rebalance, fetched records of partitions which may be assigned to other member are dropped
+            if !self.is_group_member() {
+                self.record_buffer.clear();
+            }
+        }
+
+        if !self.is_group_member() {
+            self.join_group()?;
+            self.list_offsets()?;
+        }
+
+        if self.record_buffer.is_empty() {
+            if self.state.metadata_refresh_needed {
+                self.do_call::<MetadataRequest, MetadataResponse>(ApiKey::MetadataKey)?;
+            }
+
+            if self.state.position_validation_needed() {
+                self.validate_positions()?;
+            }
+
+            if self.state.offset_listing_needed() {
+                self.list_offsets()?;
+            }
+
+            let fetched = self.do_call_fetch()?;
+            self.record_buffer.extend(fetched);
+        }
+
+        let result = self.drain_record_buffer()?;
+        let records = self.deserialize_records(result);
+
+        Ok(self.intercept_consumed(records))
+    }
+
+    // commits records returned by last poll (unless auto commit is disabled) and leaves the group
+    pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.is_group_member() {
+            self.auto_commit_consumed_offsets()?;
+            self.leave_group()?;
+        }
+
+        Ok(())
+    }
+
+    // commits records returned by polls so far, meant for consumer with auto commit disabled
+    pub fn commit_sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.commit_consumed_offsets()
+    }
+
+    // offsets (of the next record to consume) of partitions whose records were returned by poll since they were last committed,
+    // passed to Producer::send_offsets_to_transaction together with group_metadata
+    pub fn consumed_offsets(&self) -> HashMap<TopicPartition, i64> {
+        self.state.uncommitted_offsets()
+    }
+
+    // membership of the consumer, passed to Producer::send_offsets_to_transaction so the group coordinator
+    // rejects offsets of a consumer which was meanwhile fenced by rebalance
+    pub fn group_metadata(&self) -> Result<ConsumerGroupMetadata, KafkaCallerError> {
+        Ok(
+            ConsumerGroupMetadata {
+                group_id: self.state.configuration.group_id()?,
+                generation_id: self.state.group_subscription.generation_id,
+                member_id: self.state.group_subscription.member_id.clone(),
+                // static membership is not supported
+                group_instance_id: None,
+            }
+        )
+    }
+
+    fn is_group_member(&self) -> bool {
+        self.state.group_subscription.generation_id != -1
+    }
+
+    fn join_group(&mut self) -> Result<(), Box<dyn Error>> {
         self.do_call::<ApiVersionsRequest, ApiVersionsResponse>(ApiKey::ApiVersionsKey)?;
         self.do_call::<MetadataRequest, MetadataResponse>(ApiKey::MetadataKey)?;
         self.do_call::<FindCoordinatorRequest, FindCoordinatorResponse>(ApiKey::FindCoordinatorKey)?;
-        // first join group returns member id, second performs proper join group
-        self.do_call::<JoinGroupRequest, JoinGroupResponse>(ApiKey::JoinGroupKey)?;
+        // first join group returns member id, second performs proper join group (member which already has id joins with the first one)
         self.do_call::<JoinGroupRequest, JoinGroupResponse>(ApiKey::JoinGroupKey)?;
+        if !self.is_group_member() {
+            self.do_call::<JoinGroupRequest, JoinGroupResponse>(ApiKey::JoinGroupKey)?;
+        }
         // sync group is called because otherwise heartbeat returns error
         self.do_call::<SyncGroupRequest, SyncGroupResponse>(ApiKey::SyncGroupKey)?;
-        // heartbeat works and returns error_code 0, so is probably correct for this simple use-case.
-        // however it sends static data (with exception of correlation id) to simplify implementation
-        // by not having to make part of CallSate thread safe
-        //self.run_heartbeat()?;
+        self.publish_membership()?;
         self.do_call::<OffsetFetchRequest, OffsetFetchResponse>(ApiKey::OffsetFetchKey)?;
-        self.do_call::<ListOffsetsRequest, ListOffsetsResponse>(ApiKey::ListOffsetsKey)?;
-        let result = self.do_call_fetch()?;
-        if !result.is_empty() {
-            self.do_call::<OffsetCommitRequest, OffsetCommitResponse>(ApiKey::OffsetCommitKey)?;  
+
+        Ok(())
+    }
+
+    // Poll resets max poll interval of the heartbeat thread. Membership which the thread ended meanwhile is reset the same way
+    // as by heartbeat response, records fetched for it are dropped, as the partitions can be assigned to other member
+    fn poll_started(&mut self) {
+        let membership_loss = {
+            let (lock, _) = &*self.heartbeat;
+            let mut heartbeat = lock.lock().unwrap();
+
+            heartbeat.last_poll = Instant::now();
+            heartbeat.membership_loss.take()
         };
-        self.do_call::<LeaveGroupRequest, LeaveGroupResponse>(ApiKey::LeaveGroupKey)?;
- 
+
+        if let Some(membership_loss) = membership_loss {
+            self.state.group_subscription.generation_id = -1;
+            if membership_loss == MembershipLoss::Removed {
+                self.state.group_subscription.member_id = String::default();
+            }
+
+            self.state.fetch_state.clear();
+            self.record_buffer.clear();
+        }
+    }
+
+    // heartbeat thread is started on first join, as it needs api versions of the broker.
+    // Membership ended by the heartbeat thread is kept ended until poll finds out about it
+    fn publish_membership(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.heartbeat_thread.is_none() && self.is_group_member() {
+            let heartbeat = Heartbeat::new(&self.state.configuration, self.state.broker_api_versions.clone(), self.heartbeat.clone())?;
+            self.heartbeat_thread = Some(thread::spawn(move || heartbeat.run()));
+        }
+
+        let (lock, _) = &*self.heartbeat;
+        let mut heartbeat = lock.lock().unwrap();
+
+        if heartbeat.membership_loss.is_none() {
+            heartbeat.group_subscription = self.is_group_member().then(|| self.state.group_subscription.clone());
+        }
+
+        Ok(())
+    }
+
+    // fetched but not returned records are dropped, as the partitions can be assigned to other member
+    fn leave_group(&mut self) -> Result<(), Box<dyn Error>> {
+        self.record_buffer.clear();
+
+        // member was already removed from the group by coordinator (UNKNOWN_MEMBER_ID), there is nothing to leave
+        if self.state.group_subscription.member_id.is_empty() {
+            return self.publish_membership();
+        }
+
+        let result = self.do_call::<LeaveGroupRequest, LeaveGroupResponse>(ApiKey::LeaveGroupKey);
+        self.publish_membership()?;
+
+        result
+    }
+
+    fn auto_commit_consumed_offsets(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.state.configuration.enable_auto_commit()? {
+            self.commit_consumed_offsets()?;
+        }
+
+        Ok(())
+    }
+
+    fn commit_consumed_offsets(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.state.has_uncommitted_offsets() {
+            let offsets = self.state.uncommitted_offsets();
+            self.do_call::<OffsetCommitRequest, OffsetCommitResponse>(ApiKey::OffsetCommitKey)?;
+
+            // commit was rejected due to rebalance, offsets were dropped
+            if !self.is_group_member() {
+                return self.publish_membership();
+            }
+
+            for interceptor in self.interceptors.iter_mut() {
+                interceptor.on_commit(&offsets);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn intercept_consumed(&mut self, records: ConsumerRecords<K, V>) -> ConsumerRecords<K, V> {
+        self.interceptors
+            .iter_mut()
+            .fold(records, |records, interceptor| interceptor.on_consume(records))
+    }
+
+    // records which cannot be deserialized are reported in result instead of failing whole poll
+    fn deserialize_records(&self, records: ConsumerRecords) -> ConsumerRecords<K, V> {
+        let mut result = ConsumerRecords::default();
+
+        for record in records {
+            match record.deserialize(self.key_deserializer.as_ref(), self.value_deserializer.as_ref()) {
+                Ok(deserialized_record) => result.push(deserialized_record),
+                Err(deserialization_error) => result.push_error(deserialization_error),
+            }
+        }
+
+        result
+    }
+
+    // takes at most max poll records from buffer, these are considered consumed and are committed by next commit
+    fn drain_record_buffer(&mut self) -> Result<ConsumerRecords, Box<dyn Error>> {
+        let drained_count = min(self.state.configuration.max_poll_records()?, self.record_buffer.len());
+        let mut result = ConsumerRecords::default();
+
+        for record in self.record_buffer.drain(..drained_count) {
+            if let Some(offset_state) = 
+                self.state.fetch_state
+                    .get_mut(&record.topic)
+                    .and_then(|partitions| partitions.get_mut(&record.partition)) 
+            {
+                if record.offset > offset_state.consumed_offset {
+                    offset_state.consumed_offset = record.offset;
+                    offset_state.consumed_leader_epoch = record.leader_epoch.unwrap_or(-1);
+                }
+            }
+
+            result.push(record);
+        }
+
         Ok(result)
     }
 
@@ -135,80 +728,233 @@ impl Consumer {
         Ok(())
     }
 
-    // this is separate because this one returns vector and also response implements different trait
-    fn do_call_fetch(&mut self) -> Result<Vec<Record>, Box<dyn Error>> {
-        let ser_de: SerDe<FetchRequest, FetchResponse> = ApiKey::FetchKey.new_ser_de(Some(&self.state))?;
+    // this is separate because this one returns vector and also response implements different trait.
+    // Fetch is sent to every broker which leads (or is preferred read replica for) some of the fetched partitions.
+    fn do_call_fetch(&mut self) -> Result<ConsumerRecords, Box<dyn Error>> {
+        let mut result = ConsumerRecords::default();
+
+        for node_id in self.state.fetch_node_ids() {
+            self.state.target_node_id = node_id;
+
+            let ser_de: SerDe<FetchRequest, FetchResponse> = ApiKey::FetchKey.new_ser_de(Some(&self.state))?;
+
+            let request_body = FetchRequest::default().create_request(&self.state)?;
+
+            println!("{:#?}", request_body);
+
+            let request_bytes = 
+                ser_de.serialize(
+                    &self.state.configuration.client_id(), 
+                    self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),  
+                    request_body
+                )?;
+
+            let mut response_bytes = self.call_on_node(node_id, request_bytes)?;
+
+            let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
+
+            println!("{:#?}", response_body);
+
+            // this comes from different trait than other process_response methods - it returns vector of records besides modifying state
+            result.append(response_body.process_response(&mut self.state)?);
+        }
+
+        if result.is_empty() {
+            if let Some(fetch_error) = self.state.pending_fetch_error.take() {
+                return Err(Box::new(fetch_error));
+            }
+        }
+
+        Ok(result)
+    }
+
+    // offsets are listed by leader of each partition, otherwise brokers answer NOT_LEADER_OR_FOLLOWER
+    fn list_offsets(&mut self) -> Result<(), Box<dyn Error>> {
+        for node_id in self.state.partition_leader_ids() {
+            self.do_call_on_node::<ListOffsetsRequest, ListOffsetsResponse>(ApiKey::ListOffsetsKey, node_id)?;
+        }
+
+        Ok(())
+    }
+
+    // position validation is sent to leader of each partition, truncation is reported as fetch error
+    fn validate_positions(&mut self) -> Result<(), Box<dyn Error>> {
+        for node_id in self.state.position_validation_node_ids() {
+            self.do_call_on_node::<OffsetForLeaderEpochRequest, OffsetForLeaderEpochResponse>(ApiKey::OffsetForLeaderEpochKey, node_id)?;
+        }
+
+        Ok(())
+    }
+
+    // same as do_call, but request is sent to the given broker instead of the bootstrap one
+    fn do_call_on_node<Req, Res>(&mut self, api_key: ApiKey, node_id: i32) -> Result<(), Box<dyn Error>>
+        where
+            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        self.state.target_node_id = node_id;
+
+        let ser_de: SerDe<Req, Res> = api_key.new_ser_de(Some(&self.state))?;
 
-        let request_body = FetchRequest::default().create_request(&self.state)?;
+        let request_body = Req::default().create_request(&self.state)?;
 
         println!("{:#?}", request_body);
 
-        let mut response_bytes = self.io.call(
+        let request_bytes = 
             ser_de.serialize(
                 &self.state.configuration.client_id(), 
                 self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),  
                 request_body
-            )?
-        )?;
+            )?;
+
+        let mut response_bytes = self.call_on_node(node_id, request_bytes)?;
 
         let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
 
         println!("{:#?}", response_body);
 
-        // this comes from different trait than other process_response methods - it returns vector of records besides modifying state
-        response_body.process_response(&mut self.state)
+        response_body.process_response(&mut self.state)?;
+
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    // this is a super-naive implementation that sends fixed request data to avoid having to make parts of CallState thread-safe
-    fn run_heartbeat(&self) -> Result<(), Box<dyn Error>>  {
-        let mut heartbeat_io = self.io.try_clone()?;
-        let ser_de: SerDe<HeartbeatRequest, HeartbeatResponse> = ApiKey::HeartbeatKey.new_ser_de(Some(&self.state)).unwrap();
-        let request_body = HeartbeatRequest::default().create_request(&self.state)?;
-        let atomic = self.state.correlation_id.clone();
-        let client_id = self.state.configuration.client_id().clone();
+    // Connection which failed is dropped and opened again by the next request, so a restarted broker is reachable again.
+    // Partitions redirected to the node as preferred read replica go back to their leader, metadata is refreshed in case leaders moved.
+    fn call_on_node(&mut self, node_id: i32, request_bytes: BytesMut) -> Result<Bytes, Box<dyn Error>> {
+        let result =
+            self.fetch_io(node_id)
+                .and_then(|io| io.call(request_bytes));
 
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(5000));
+        if result.is_err() {
+            self.fetch_connections.remove(&node_id);
+            self.state.metadata_refresh_needed = true;
 
-                let request_body = request_body.clone();
+            self.state.fetch_state
+                .values_mut()
+                .flat_map(|partitions| partitions.values_mut())
+                .filter(|offset_state| offset_state.preferred_read_replica == node_id)
+                .for_each(|offset_state| offset_state.preferred_read_replica = -1);
+        }
 
-                println!("{:#?}", request_body);
+        result
+    }
 
-                let mut response_bytes = 
-                    heartbeat_io.call(
-                        ser_de.serialize(
-                                    &client_id, 
-                                    atomic.fetch_add(1, std::sync::atomic::Ordering::Relaxed), 
-                                    request_body
-                                ).unwrap()
-                    ).unwrap();
+    // connections used for fetching are opened lazily to brokers from metadata
+    fn fetch_io(&mut self, node_id: i32) -> Result<&mut IO, Box<dyn Error>> {
+        match self.fetch_connections.entry(node_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let broker = 
+                    self.state.broker_metadata.brokers
+                        .get(&node_id)
+                        .ok_or(KafkaCallerError::new(&format!("Could not find broker '{}' in stored metadata", node_id)))?;
+                let tcp_stream = TcpStream::connect(format!("{}:{}", broker.host, broker.port))?;
 
-                let (_, response_body) = ser_de.deserialize(&mut response_bytes).unwrap();
+                Ok(entry.insert(IO::from(tcp_stream)))
+            }
+        }
+    }
+}
+
+// Blocking iteration over consumed records, it calls poll whenever records of previous poll were all returned and never ends.
+// Records which could not be deserialized are returned as errors in their place, so iteration can continue after them.
+impl<K, V> Iterator for Consumer<K, V> {
+    type Item = Result<ConsumerRecord<K, V>, Box<dyn Error>>;
 
-                println!("{:#?}", response_body);
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.iterated_records.is_empty() {
+            match self.poll() {
+                Ok(records) => self.iterated_records.extend(records.into_results()),
+                Err(error) => return Some(Err(error)),
             }
-        });
+        }
 
-        Ok(())
+        self.iterated_records
+            .pop_front()
+            .map(|result| result.map_err(|deserialization_error| -> Box<dyn Error> { Box::new(deserialization_error) }))
     }
 }
 
-pub struct Producer {
-    state: CallState,
-    io: IO,
+// heartbeat thread is stopped, call it is in the middle of is bounded by session timeout
+impl<K, V> Drop for Consumer<K, V> {
+    fn drop(&mut self) {
+        {
+            let (lock, condvar) = &*self.heartbeat;
+            lock.lock().unwrap().closed = true;
+            condvar.notify_all();
+        }
+
+        if let Some(heartbeat_thread) = self.heartbeat_thread.take() {
+            let _ = heartbeat_thread.join();
+        }
+    }
+}
+
+// Records passed to send are partitioned and appended to the accumulator on the calling thread,
+// sender thread started with the producer sends them to partition leaders in batches.
+pub struct Producer<K = Bytes, V = Bytes> {
+    accumulator: SharedAccumulator,
+    sender_thread: Option<JoinHandle<()>>,
+    key_serializer: Box<dyn Serializer<K> + Send>,
+    value_serializer: Box<dyn Serializer<V> + Send>,
+    partitioner: Box<dyn Partitioner + Send>,
+    transactional: bool,
+    transaction_state: TransactionState,
+    max_request_size: usize,
+    interceptors: Vec<Arc<dyn ProducerInterceptor<K, V>>>,
+}
+
+// state of transactional producer, records can be sent only in transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransactionState {
+    Uninitialized,
+    Ready,
+    InTransaction,
 }
 
 impl Producer {
+    // producer putting raw key and value bytes
     pub fn new(configuration: &Configuration) -> Result<Self, Box<dyn Error>> {
-        if let Configuration::ProducerConfiguration{broker_address, ..} = configuration {
+        Self::with_serializers(configuration, BytesSerializer, BytesSerializer)
+    }
+}
+
+impl<K, V> Producer<K, V> {
+    pub fn with_serializers(
+        configuration: &Configuration, 
+        key_serializer: impl Serializer<K> + Send + 'static, 
+        value_serializer: impl Serializer<V> + Send + 'static
+    ) -> Result<Self, Box<dyn Error>> {
+        if let Configuration::ProducerConfiguration{broker_address, batch_size, linger_ms, buffer_memory, delivery_timeout_ms, acks, enable_idempotence, transactional_id, compression_type, compression_level, max_request_size, ..} = configuration {
+            if *enable_idempotence && *acks != Acks::All {
+                return Err(Box::new(KafkaCallerError::new("Idempotent producer requires acks All")));
+            }
+
+            if transactional_id.is_some() && !*enable_idempotence {
+                return Err(Box::new(KafkaCallerError::new("Transactional producer requires idempotence")));
+            }
+
+            if let Some(compression_level) = compression_level {
+                compression_type.check_level(*compression_level)?;
+            }
+
             let tcp_stream = TcpStream::connect(broker_address)?;
 
+            let accumulator: SharedAccumulator = 
+                Arc::new((Mutex::new(RecordAccumulator::new(*batch_size, *linger_ms, *buffer_memory, *delivery_timeout_ms)), Condvar::new()));
+            let sender = Sender::new(configuration, IO::from(tcp_stream), accumulator.clone())?;
+
             Ok(
                 Self {
-                    state: CallState::new(configuration)?,
-                    io: IO::from(tcp_stream),
+                    accumulator,
+                    sender_thread: Some(thread::spawn(move || sender.run())),
+                    key_serializer: Box::new(key_serializer),
+                    value_serializer: Box::new(value_serializer),
+                    partitioner: Box::new(DefaultPartitioner::with_batch_size(*batch_size)),
+                    transactional: transactional_id.is_some(),
+                    transaction_state: TransactionState::Uninitialized,
+                    max_request_size: *max_request_size,
+                    interceptors: Vec::new(),
                 }
             )
         } else {
@@ -216,49 +962,287 @@ impl Producer {
         }
     }
 
-    // This copies sequence of calls performed by java client when putting records into topic. for the first time.
-    // Implemented and tested to work with one topic with 1 partition on docker setup with one broker only.
-    // Put works correctly, even on repeated calls, however this is probably not entirely correct, as it does not fully match java client
-    // when performing more than one call. Put theoretically supports multiple topics (each having one partition, and one broker only), but that remains untested.
-    // (there are more calls performed by java client in practice, especially several ApiVersions calls, but this is enough to correctly put entries)
-    pub fn put(&mut self, records: &mut Vec<PutRecord>) -> Result<(), Box<dyn Error>> {
-        self.state.connected_topics = extract_topics(records);
-        self.state.records_to_send.append(records);
-
-        self.do_call::<ApiVersionsRequest, ApiVersionsResponse>(ApiKey::ApiVersionsKey)?;
-        self.do_call::<MetadataRequest, MetadataResponse>(ApiKey::MetadataKey)?;
-        self.do_call::<InitProducerIdRequest, InitProducerIdResponse>(ApiKey::InitProducerIdKey)?;
-        
-        let result = self.do_call::<ProduceRequest, ProduceResponse>(ApiKey::ProduceKey);
-        self.state.records_to_send.clear();
-        result
+    // replaces DefaultPartitioner, used for records without explicit partition
+    pub fn set_partitioner(&mut self, partitioner: impl Partitioner + Send + 'static) {
+        self.partitioner = Box::new(partitioner);
     }
 
-    fn do_call<Req, Res>(&mut self, api_key: ApiKey) -> Result<(), Box<dyn Error>>
+    // interceptors are called in the order they were added, acknowledgements only for records sent after they were added
+    pub fn add_interceptor(&mut self, interceptor: impl ProducerInterceptor<K, V> + 'static)
         where
-            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
-            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+            K: 'static,
+            V: 'static
     {
-        let ser_de: SerDe<Req, Res> = api_key.new_ser_de(Some(&self.state))?;
+        self.interceptors.push(Arc::new(interceptor));
 
-        let request_body = Req::default().create_request(&self.state)?;
+        let interceptors = self.interceptors.clone();
+        let (lock, _) = &*self.accumulator;
+        lock.lock().unwrap().acknowledgement_hook = 
+            Some(AcknowledgementHook(Arc::new(move |result: &Result<RecordMetadata, KafkaCallerError>| {
+                for interceptor in interceptors.iter() {
+                    interceptor.on_acknowledgement(result.as_ref());
+                }
+            })));
+    }
 
-        println!("{:#?}", request_body);
+    // Serializes the record and appends it to the accumulator, returns without waiting for the record to be sent.
+    // Blocks only while metadata of a new topic is fetched or when buffer memory is exhausted.
+    pub fn send(&mut self, mut record: PutRecord<K, V>) -> Result<SendHandle, Box<dyn Error>> {
+        self.intercept_send(&mut record);
+        let serialized_record = record.serialize(self.key_serializer.as_ref(), self.value_serializer.as_ref())?;
 
-        let mut response_bytes = self.io.call(
-            ser_de.serialize(
-                &self.state.configuration.client_id(), 
-                self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),  
-                request_body
-            )?
-        )?;
+        Ok(self.send_serialized(serialized_record)?)
+    }
 
-        let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
+    // Sends records and waits until all of them are delivered (batches are sent after linger.ms at the latest),
+    // returns metadata of delivered records in the same order. Fails with the first record which was not delivered.
+    // Records are serialized before anything is sent, so when serialization of any of them fails, none is put.
+    pub fn put(&mut self, records: &mut Vec<PutRecord<K, V>>) -> Result<Vec<RecordMetadata>, Box<dyn Error>> {
+        records.iter_mut().for_each(|record| self.intercept_send(record));
 
-        println!("{:#?}", response_body);
+        let serialized_records = 
+            records
+                .iter()
+                .map(|record| record.serialize(self.key_serializer.as_ref(), self.value_serializer.as_ref()))
+                .collect::<Result<Vec<PutRecord>, Box<dyn Error>>>()?;
+        records.clear();
+
+        let handles =
+            serialized_records
+                .into_iter()
+                .map(|record| self.send_serialized(record))
+                .collect::<Result<Vec<SendHandle>, KafkaCallerError>>()?;
+
+        let mut records_metadata = Vec::with_capacity(handles.len());
+        for handle in handles {
+            records_metadata.push(handle.wait()?);
+        }
+
+        Ok(records_metadata)
+    }
+
+    // Blocks until every record sent so far is acknowledged or failed, batches are sent without waiting for linger.
+    // Failures of single records are reported by their handles, error is returned only when sender thread stopped.
+    pub fn flush(&mut self) -> Result<(), KafkaCallerError> {
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        accumulator.flush_requested = true;
+        condvar.notify_all();
+
+        while !accumulator.is_done() && accumulator.sender_error.is_none() {
+            accumulator = condvar.wait(accumulator).unwrap();
+        }
+
+        match &accumulator.sender_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    // Sends records still in the accumulator and waits up to timeout for them, records which were not sent by then are failed.
+    // Sender thread is joined, so batch in flight is still answered (bounded by request timeout), then all connections are closed.
+    // Returns error with the number of records which could not be delivered because of the timeout.
+    pub fn close(mut self, timeout: Duration) -> Result<(), KafkaCallerError> {
+        let deadline = Instant::now() + timeout;
+        let undelivered_records = {
+            let (lock, condvar) = &*self.accumulator;
+            let mut accumulator = lock.lock().unwrap();
+
+            accumulator.closed = true;
+            condvar.notify_all();
+
+            while !accumulator.is_done() && accumulator.sender_error.is_none() && Instant::now() < deadline {
+                accumulator = condvar.wait_timeout(accumulator, deadline.saturating_duration_since(Instant::now())).unwrap().0;
+            }
+
+            // records of batch in flight are reported by their handles
+            accumulator.force_closed = !accumulator.is_done();
+            let undelivered_records = accumulator.abort_batches(KafkaCallerError::new("Producer was closed before the record was sent"));
+            condvar.notify_all();
+
+            undelivered_records
+        };
+
+        if let Some(sender_thread) = self.sender_thread.take() {
+            let _ = sender_thread.join();
+        }
+
+        if undelivered_records > 0 {
+            return Err(KafkaCallerError::new(&format!("{} records could not be delivered within close timeout of {} ms", undelivered_records, timeout.as_millis())));
+        }
+
+        let (lock, _) = &*self.accumulator;
+        match &lock.lock().unwrap().sender_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    // Gets producer id and epoch from transaction coordinator, fencing off previous producers with the same transactional id
+    // and completing their pending transactions. Has to be called once before the first transaction.
+    pub fn init_transactions(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.transactional {
+            return Err(Box::new(KafkaCallerError::new("Transactions require transactional id in producer configuration")));
+        }
+
+        if self.transaction_state != TransactionState::Uninitialized {
+            return Err(Box::new(KafkaCallerError::new("Transactions were already initialized")));
+        }
+
+        self.run_transaction_command(TransactionCommand::Init)?;
+        self.transaction_state = TransactionState::Ready;
+
+        Ok(())
+    }
+
+    pub fn begin_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.transaction_state {
+            TransactionState::Ready => {
+                self.transaction_state = TransactionState::InTransaction;
+                Ok(())
+            },
+            TransactionState::Uninitialized => Err(Box::new(KafkaCallerError::new("Transactions were not initialized, call init_transactions first"))),
+            TransactionState::InTransaction => Err(Box::new(KafkaCallerError::new("Transaction is already in progress"))),
+        }
+    }
+
+    // Sends all records of the transaction and commits it. When some of them failed, transaction is not committed
+    // and has to be aborted.
+    pub fn commit_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.transaction_state != TransactionState::InTransaction {
+            return Err(Box::new(KafkaCallerError::new("No transaction in progress")));
+        }
+
+        self.run_transaction_command(TransactionCommand::Commit)?;
+        self.transaction_state = TransactionState::Ready;
 
-        response_body.process_response(&mut self.state)?;
-        
         Ok(())
     }
+
+    // records of the transaction which were not sent yet are failed, those sent are not visible to read committed consumers
+    pub fn abort_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.transaction_state != TransactionState::InTransaction {
+            return Err(Box::new(KafkaCallerError::new("No transaction in progress")));
+        }
+
+        self.run_transaction_command(TransactionCommand::Abort)?;
+        self.transaction_state = TransactionState::Ready;
+
+        Ok(())
+    }
+
+    // Offsets (of the next record to consume) are committed for consumer group as part of the transaction, so they become visible
+    // only when transaction is committed, which gives exactly once consume-transform-produce
+    pub fn send_offsets_to_transaction(&mut self, offsets: HashMap<TopicPartition, i64>, group_metadata: &ConsumerGroupMetadata) -> Result<(), Box<dyn Error>> {
+        if self.transaction_state != TransactionState::InTransaction {
+            return Err(Box::new(KafkaCallerError::new("No transaction in progress")));
+        }
+
+        if offsets.is_empty() {
+            return Ok(());
+        }
+
+        self.run_transaction_command(TransactionCommand::SendOffsets { offsets, group_metadata: group_metadata.clone() })?;
+
+        Ok(())
+    }
+
+    // command is performed by sender thread, which is woken up and waited for
+    fn run_transaction_command(&mut self, command: TransactionCommand) -> Result<(), KafkaCallerError> {
+        let (result_sender, result_receiver) = std::sync::mpsc::channel();
+
+        {
+            let (lock, condvar) = &*self.accumulator;
+            let mut accumulator = lock.lock().unwrap();
+
+            if let Some(error) = &accumulator.sender_error {
+                return Err(error.clone());
+            }
+
+            // commit sends records of the transaction without waiting for linger
+            if let TransactionCommand::Commit = command {
+                accumulator.flush_requested = true;
+            }
+
+            accumulator.transaction_commands.push_back((command, result_sender));
+            condvar.notify_all();
+        }
+
+        result_receiver
+            .recv()
+            .unwrap_or_else(|_| Err(KafkaCallerError::new("Producer was closed before transaction command was performed")))
+    }
+
+    fn intercept_send(&self, record: &mut PutRecord<K, V>) {
+        for interceptor in self.interceptors.iter() {
+            interceptor.on_send(record);
+        }
+    }
+
+    fn send_serialized(&mut self, mut record: PutRecord) -> Result<SendHandle, KafkaCallerError> {
+        if self.transactional && self.transaction_state != TransactionState::InTransaction {
+            return Err(KafkaCallerError::new("Transactional producer can send records only within transaction"));
+        }
+
+        if let Some(timestamp) = record.timestamp.filter(|timestamp| *timestamp < 0) {
+            return Err(KafkaCallerError::new(&format!("Invalid timestamp '{}', timestamp cannot be negative", timestamp)));
+        }
+
+        // same as RecordTooLargeException of java client, such record could never be sent
+        let record_size = record.size_in_bytes();
+        if record_size + MAX_RECORD_OVERHEAD + RECORD_BATCH_OVERHEAD > self.max_request_size {
+            return Err(KafkaCallerError::new(&format!(
+                "The record is {} bytes when serialized which is larger than {}, the value of max request size", 
+                record_size + MAX_RECORD_OVERHEAD + RECORD_BATCH_OVERHEAD, self.max_request_size
+            )));
+        }
+
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        // partitions of a topic are not known until sender thread fetches its metadata
+        if !accumulator.broker_metadata.topics.contains_key(&record.topic) {
+            accumulator.pending_topics.insert(record.topic.clone());
+            condvar.notify_all();
+
+            while accumulator.pending_topics.contains(&record.topic) && accumulator.sender_error.is_none() {
+                accumulator = condvar.wait(accumulator).unwrap();
+            }
+        }
+
+        if let Some(error) = &accumulator.sender_error {
+            return Err(error.clone());
+        }
+
+        assign_partitions(self.partitioner.as_mut(), &accumulator.broker_metadata, std::slice::from_mut(&mut record))?;
+
+        while !accumulator.has_room(record_size) && accumulator.sender_error.is_none() {
+            accumulator = condvar.wait(accumulator).unwrap();
+        }
+
+        if let Some(error) = &accumulator.sender_error {
+            return Err(error.clone());
+        }
+
+        let handle = accumulator.append(record);
+        condvar.notify_all();
+
+        Ok(handle)
+    }
+}
+
+// records still in the accumulator are sent before the sender thread finishes
+impl<K, V> Drop for Producer<K, V> {
+    fn drop(&mut self) {
+        {
+            let (lock, condvar) = &*self.accumulator;
+            lock.lock().unwrap().closed = true;
+            condvar.notify_all();
+        }
+
+        if let Some(sender_thread) = self.sender_thread.take() {
+            let _ = sender_thread.join();
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use rand::Rng;
+
+use crate::errors::KafkaCallerError;
+use crate::io::call_state::BrokerMetadata;
+use crate::io::records::PutRecord;
+
+// batch.size default of java client, sticky partition is switched after this many bytes
+const DEFAULT_STICKY_BATCH_SIZE: usize = 16384;
+
+// chooses partition of records which do not have partition set explicitly.
+// partitions holds every partition index of the topic (sorted), available_partitions only those with a known leader
+pub trait Partitioner {
+    fn partition(&mut self, topic: &str, key: Option<&Bytes>, value: Option<&Bytes>, partitions: &[i32], available_partitions: &[i32]) -> i32;
+}
+
+// java client default: keyed records are hashed by murmur2, keyless ones use sticky partitioner
+#[derive(Debug, Default)]
+pub struct DefaultPartitioner {
+    sticky_partitioner: StickyPartitioner,
+}
+
+impl DefaultPartitioner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_batch_size(batch_size: usize) -> Self {
+        Self {
+            sticky_partitioner: StickyPartitioner::with_batch_size(batch_size),
+        }
+    }
+}
+
+impl Partitioner for DefaultPartitioner {
+    fn partition(&mut self, topic: &str, key: Option<&Bytes>, value: Option<&Bytes>, partitions: &[i32], available_partitions: &[i32]) -> i32 {
+        match key {
+            Some(key) => partitions[partition_for_key(key, partitions.len() as i32) as usize],
+            None => self.sticky_partitioner.partition(topic, key, value, partitions, available_partitions),
+        }
+    }
+}
+
+// uniform sticky partitioner (KIP-794), keys are ignored. Records of a topic stick to one randomly chosen
+// available partition until batch_size bytes were produced to it, then another partition is chosen.
+#[derive(Debug)]
+pub struct StickyPartitioner {
+    batch_size: usize,
+    // topic -> (sticky partition, bytes produced to it)
+    sticky_partitions: HashMap<String, (i32, usize)>,
+}
+
+impl Default for StickyPartitioner {
+    fn default() -> Self {
+        Self::with_batch_size(DEFAULT_STICKY_BATCH_SIZE)
+    }
+}
+
+impl StickyPartitioner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_batch_size(batch_size: usize) -> Self {
+        Self {
+            batch_size,
+            sticky_partitions: HashMap::default(),
+        }
+    }
+}
+
+impl Partitioner for StickyPartitioner {
+    fn partition(&mut self, topic: &str, key: Option<&Bytes>, value: Option<&Bytes>, partitions: &[i32], available_partitions: &[i32]) -> i32 {
+        let record_size = 
+            key.map(|key| key.len()).unwrap_or(0) + 
+            value.map(|value| value.len()).unwrap_or(0);
+
+        let candidates = 
+            if available_partitions.is_empty() {
+                partitions
+            } else {
+                available_partitions
+            };
+
+        let sticky_partition = 
+            self.sticky_partitions
+                .entry(String::from(topic))
+                .or_insert((random_partition(candidates), 0));
+
+        // metadata changed and partition is gone or lost its leader, or batch is full
+        if !candidates.contains(&sticky_partition.0) || sticky_partition.1 >= self.batch_size {
+            *sticky_partition = (random_partition(candidates), 0);
+        }
+
+        sticky_partition.1 += record_size;
+        sticky_partition.0
+    }
+}
+
+// java client RoundRobinPartitioner: keys are ignored, records are spread evenly over available partitions
+#[derive(Debug, Default)]
+pub struct RoundRobinPartitioner {
+    counters: HashMap<String, usize>,
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&mut self, topic: &str, _key: Option<&Bytes>, _value: Option<&Bytes>, partitions: &[i32], available_partitions: &[i32]) -> i32 {
+        let counter = self.counters.entry(String::from(topic)).or_insert(0);
+        let next = *counter;
+        *counter = counter.wrapping_add(1);
+
+        if available_partitions.is_empty() {
+            partitions[next % partitions.len()]
+        } else {
+            available_partitions[next % available_partitions.len()]
+        }
+    }
+}
+
+// sets partition of records which do not have one, and checks explicit ones against metadata
+pub(crate) fn assign_partitions(partitioner: &mut dyn Partitioner, broker_metadata: &BrokerMetadata, records: &mut [PutRecord]) -> Result<(), KafkaCallerError> {
+    for put_record in records.iter_mut() {
+        let topic = 
+            broker_metadata.topics
+                .get(&put_record.topic)
+                .ok_or(KafkaCallerError::new(&format!("Could not find topic with name '{}' in stored metadata", put_record.topic)))?;
+
+        let mut partitions: Vec<i32> = topic.partitions.keys().copied().collect();
+        partitions.sort_unstable();
+
+        if partitions.is_empty() {
+            return Err(KafkaCallerError::new(&format!("No partitions found for topic '{}' in stored metadata", put_record.topic)));
+        }
+
+        let partition_index = match put_record.partition {
+            Some(partition_index) => partition_index,
+            None => {
+                let available_partitions: Vec<i32> = 
+                    partitions
+                        .iter()
+                        .copied()
+                        .filter(|index| topic.partitions[index].leader_id >= 0)
+                        .collect();
+
+                partitioner.partition(&put_record.topic, put_record.key.as_ref(), put_record.value.as_ref(), &partitions, &available_partitions)
+            }
+        };
+
+        if !topic.partitions.contains_key(&partition_index) {
+            return Err(KafkaCallerError::new(&format!("Partition {} does not exist for topic '{}'", partition_index, put_record.topic)));
+        }
+
+        put_record.partition = Some(partition_index);
+    }
+
+    Ok(())
+}
+
+fn random_partition(partitions: &[i32]) -> i32 {
+    partitions[rand::thread_rng().gen_range(0..partitions.len())]
+}
+
+// murmur2 as implemented by the java client (org.apache.kafka.common.utils.Utils#murmur2),
+// keyed records must land on the same partition whichever client produced them
+pub(crate) fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let length = data.len();
+    let mut h: u32 = SEED ^ length as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let remainder = chunks.remainder();
+    if remainder.len() >= 3 {
+        h ^= (remainder[2] as u32) << 16;
+    }
+    if remainder.len() >= 2 {
+        h ^= (remainder[1] as u32) << 8;
+    }
+    if !remainder.is_empty() {
+        h ^= remainder[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+
+    h as i32
+}
+
+// same as java Utils#toPositive, drops the sign bit instead of abs() so i32::MIN stays valid
+pub(crate) fn to_positive(number: i32) -> i32 {
+    number & 0x7fffffff
+}
+
+pub(crate) fn partition_for_key(key: &[u8], partition_count: i32) -> i32 {
+    to_positive(murmur2(key)) % partition_count
+}
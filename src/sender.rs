@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::fmt::Debug;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use bytes::BytesMut;
+use indexmap::IndexMap;
+use kafka_protocol::messages::{ApiKey, ApiVersionsRequest, ApiVersionsResponse, MetadataRequest, MetadataResponse, InitProducerIdRequest, InitProducerIdResponse, ProduceRequest, ProduceResponse, FindCoordinatorRequest, FindCoordinatorResponse, AddPartitionsToTxnRequest, AddPartitionsToTxnResponse, EndTxnRequest, EndTxnResponse, AddOffsetsToTxnRequest, AddOffsetsToTxnResponse, TxnOffsetCommitRequest, TxnOffsetCommitResponse};
+use kafka_protocol::protocol::{Decodable, Encodable, Message, HeaderVersion};
+use crate::{Acks, Configuration, ConsumerGroupMetadata};
+use crate::errors::KafkaCallerError;
+use crate::io::IO;
+use crate::io::accumulator::{ProducerBatch, SharedAccumulator, TransactionCommand, TransactionResult, increment_sequence, RETRY_BACKOFF, MAX_RECORD_OVERHEAD, RECORD_BATCH_OVERHEAD};
+use crate::io::call_state::{CallState, GROUP_COORDINATOR, TRANSACTION_COORDINATOR};
+use crate::io::records::TopicPartition;
+use crate::io::messages::{CreateSerDe, SerDe, CreateRequest, ProcessResponse};
+
+// work picked from accumulator for one iteration of the sender loop
+struct SenderWork {
+    topics: Vec<String>,
+    batches: Vec<ProducerBatch>,
+    transaction_command: Option<(TransactionCommand, mpsc::Sender<TransactionResult>)>,
+}
+
+// Background part of the producer. It runs on its own thread, fetches metadata of topics the producer asks for,
+// drains batches which are ready from the accumulator and sends them in one Produce request per leader broker.
+pub(crate) struct Sender {
+    state: CallState,
+    io: IO,
+    // connections to partition leaders are opened lazily to brokers from metadata
+    connections: HashMap<i32, IO>,
+    accumulator: SharedAccumulator,
+}
+
+impl Sender {
+    pub fn new(configuration: &Configuration, io: IO, accumulator: SharedAccumulator) -> Result<Self, Box<dyn Error>> {
+        io.set_timeout(Duration::from_millis(configuration.request_timeout_ms()? as u64))?;
+
+        Ok(
+            Self {
+                state: CallState::new(configuration)?,
+                io,
+                connections: HashMap::new(),
+                accumulator,
+            }
+        )
+    }
+
+    pub fn run(mut self) {
+        if let Err(error) = self.initialize() {
+            self.stop(KafkaCallerError::new(&error.to_string()));
+            return;
+        }
+
+        while let Some(work) = self.next_work() {
+            if !work.topics.is_empty() {
+                self.refresh_metadata(work.topics);
+            }
+
+            if !work.batches.is_empty() {
+                let batch_count = work.batches.len();
+                self.send_batches(work.batches);
+                self.batches_sent(batch_count);
+            }
+
+            if let Some((command, result_sender)) = work.transaction_command {
+                let result = 
+                    self.run_transaction_command(command)
+                        .map_err(|error| KafkaCallerError::new(&error.to_string()));
+
+                let _ = result_sender.send(result);
+            }
+        }
+
+        // producer was closed and everything was sent
+        self.connections.clear();
+    }
+
+    // drained batches are completed or back in accumulator, flush and close may be waiting for it
+    fn batches_sent(&mut self, batch_count: usize) {
+        let (lock, condvar) = &*self.accumulator;
+        lock.lock().unwrap().in_flight_batches -= batch_count;
+        condvar.notify_all();
+    }
+
+    fn initialize(&mut self) -> Result<(), Box<dyn Error>> {
+        self.do_call::<ApiVersionsRequest, ApiVersionsResponse>(ApiKey::ApiVersionsKey)?;
+
+        // producer id is used only by idempotent producer, records of others are sent without it.
+        // Transactional producer gets its id from transaction coordinator in init_transactions
+        if self.state.configuration.enable_idempotence()? && self.state.configuration.transactional_id()?.is_none() {
+            self.do_call::<InitProducerIdRequest, InitProducerIdResponse>(ApiKey::InitProducerIdKey)?;
+        }
+
+        Ok(())
+    }
+
+    // blocks until there are topics to fetch metadata for, batches to send or transaction command to perform,
+    // None when producer was closed and everything was sent
+    fn next_work(&mut self) -> Option<SenderWork> {
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        loop {
+            if accumulator.force_closed {
+                accumulator.abort_batches(KafkaCallerError::new("Producer was closed before the record was sent"));
+                condvar.notify_all();
+                return None;
+            }
+
+            if let Some(error) = accumulator.fail_expired(Instant::now()) {
+                self.state.transaction_error = Some(error);
+                condvar.notify_all();
+            }
+
+            if std::mem::take(&mut accumulator.sequence_lost) {
+                self.state.producer_epoch_bump_needed = true;
+            }
+
+            if accumulator.is_empty() {
+                accumulator.flush_requested = false;
+            }
+
+            let topics: Vec<String> = accumulator.pending_topics.iter().cloned().collect();
+
+            // commit waits until all records sent before it are delivered
+            let command_ready = 
+                match accumulator.transaction_commands.front() {
+                    Some((TransactionCommand::Commit, _)) => accumulator.is_empty(),
+                    Some(_) => true,
+                    None => false,
+                };
+
+            if command_ready {
+                let transaction_command = accumulator.transaction_commands.pop_front();
+
+                if let Some((TransactionCommand::Abort, _)) = &transaction_command {
+                    accumulator.abort_batches(KafkaCallerError::new("Transaction was aborted before the record was sent"));
+                    condvar.notify_all();
+                }
+
+                return Some(SenderWork { topics, batches: Vec::new(), transaction_command });
+            }
+
+            let batches = accumulator.drain_ready(Instant::now());
+
+            if !topics.is_empty() || !batches.is_empty() {
+                accumulator.in_flight_batches += batches.len();
+
+                // drained batches freed buffer memory producer may be waiting for
+                condvar.notify_all();
+
+                return Some(SenderWork { topics, batches, transaction_command: None });
+            }
+
+            if accumulator.closed && accumulator.is_empty() {
+                return None;
+            }
+
+            accumulator = match accumulator.next_ready_in(Instant::now()) {
+                Some(timeout) => condvar.wait_timeout(accumulator, timeout).unwrap().0,
+                None => condvar.wait(accumulator).unwrap(),
+            };
+        }
+    }
+
+    // producer waits for these topics, so they are released even when metadata call fails
+    fn refresh_metadata(&mut self, topics: Vec<String>) {
+        for topic in topics.iter() {
+            if !self.state.connected_topics.contains(topic) {
+                self.state.connected_topics.push(topic.clone());
+            }
+        }
+
+        // failed refresh is repeated before the next send, records of topics still unknown fail with missing leader
+        if self.do_call::<MetadataRequest, MetadataResponse>(ApiKey::MetadataKey).is_err() {
+            self.state.metadata_refresh_needed = true;
+        }
+
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        accumulator.broker_metadata = self.state.broker_metadata.clone();
+        for topic in topics.iter() {
+            accumulator.pending_topics.remove(topic);
+        }
+
+        condvar.notify_all();
+    }
+
+    fn send_batches(&mut self, mut batches: Vec<ProducerBatch>) {
+        // batch with sequence expired since last send, new batches would be rejected as out of order.
+        // Bump needed flag stays set when the call fails, so it is tried again with the next send
+        if self.state.producer_epoch_bump_allowed() {
+            let _ = self.bump_producer_epoch();
+        }
+
+        // records sent after transaction failed would only be aborted together with it
+        if let Some(error) = self.state.transaction_error.clone().filter(|_| self.is_transactional()) {
+            let error = KafkaCallerError::new(&format!("Transaction failed before the record was sent, it has to be aborted: {}", error.0));
+
+            for batch in batches.drain(..) {
+                self.state.producer_epoch_bump_needed |= batch.base_sequence != -1;
+                batch.fail(error.clone());
+            }
+        }
+
+        let mut batches_by_node: IndexMap<i32, Vec<ProducerBatch>> = IndexMap::new();
+
+        for batch in batches {
+            let leader_id =
+                self.state.broker_metadata
+                    .partition(&batch.topic_partition.topic, batch.topic_partition.partition)
+                    .map(|partition| partition.leader_id)
+                    .filter(|leader_id| *leader_id >= 0);
+
+            match leader_id {
+                Some(leader_id) => batches_by_node.entry(leader_id).or_default().push(batch),
+                None => {
+                    let error = KafkaCallerError::new(&format!("No leader known for partition {} of topic '{}'", batch.topic_partition.partition, batch.topic_partition.topic));
+                    self.state.transaction_error = Some(error.clone());
+                    self.state.producer_epoch_bump_needed |= batch.base_sequence != -1;
+                    batch.fail(error);
+                },
+            }
+        }
+
+        let acks = self.state.configuration.acks().unwrap_or(Acks::All);
+        let max_request_size = self.state.configuration.max_request_size().unwrap_or(usize::MAX);
+
+        for (node_id, batches) in batches_by_node {
+            for request_batches in split_by_request_size(batches, max_request_size) {
+                self.send_to_node(node_id, request_batches, acks);
+            }
+        }
+
+        self.retry_batches();
+        self.split_batches();
+
+        if self.state.producer_epoch_bump_allowed() {
+            let _ = self.bump_producer_epoch();
+        }
+
+        // some partitions moved to other brokers
+        if self.state.metadata_refresh_needed {
+            self.refresh_metadata(Vec::new());
+        }
+    }
+
+    // one Produce request, batches answered with retriable error or not answered at all are retried
+    fn send_to_node(&mut self, node_id: i32, batches: Vec<ProducerBatch>, acks: Acks) {
+        self.state.batches_to_send = batches;
+        self.assign_sequences();
+
+        if self.is_transactional() {
+            self.add_partitions_to_transaction();
+
+            if self.state.batches_to_send.is_empty() {
+                return;
+            }
+        }
+
+        // broker does not answer with acks=0, records are delivered once they are written to connection
+        if acks == Acks::None {
+            match self.send_on_node::<ProduceRequest, ProduceResponse>(ApiKey::ProduceKey, node_id) {
+                Ok(_) => {
+                    for batch in self.state.batches_to_send.drain(..) {
+                        batch.complete_without_offset();
+                    }
+                },
+                Err(error) => self.connection_failed(node_id, KafkaCallerError::new(&error.to_string())),
+            }
+
+            return;
+        }
+
+        let result = self.do_call_on_node::<ProduceRequest, ProduceResponse>(ApiKey::ProduceKey, node_id);
+
+        // batches are completed by response processing, those left were not answered
+        match result {
+            Ok(_) => {
+                for batch in self.state.batches_to_send.drain(..) {
+                    let error = KafkaCallerError::new(&format!("No response for partition {} of topic '{}'", batch.topic_partition.partition, batch.topic_partition.topic));
+                    self.state.batches_to_retry.push((batch, error));
+                }
+            },
+            Err(error) => self.connection_failed(node_id, KafkaCallerError::new(&error.to_string())),
+        }
+    }
+
+    // connection may be broken, it is opened again for the next request and batches sent over it are retried
+    fn connection_failed(&mut self, node_id: i32, error: KafkaCallerError) {
+        self.connections.remove(&node_id);
+        self.state.metadata_refresh_needed = true;
+
+        for batch in self.state.batches_to_send.drain(..) {
+            self.state.batches_to_retry.push((batch, error.clone()));
+        }
+    }
+
+    // sequences are assigned only once, retried batch has to be written with the same sequence to be deduplicated
+    fn assign_sequences(&mut self) {
+        if self.state.producer_id < 0 {
+            return;
+        }
+
+        for batch in self.state.batches_to_send.iter_mut() {
+            if batch.base_sequence == -1 {
+                let next_sequence = 
+                    self.state.producer_sequences
+                        .entry((batch.topic_partition.topic.clone(), batch.topic_partition.partition))
+                        .or_insert(0);
+
+                batch.base_sequence = *next_sequence;
+                *next_sequence = increment_sequence(*next_sequence, batch.records.len() as i32);
+            }
+        }
+    }
+
+    // InitProducerId with current id and epoch bumps the epoch and keeps the id (KIP-360),
+    // sequences of all partitions start from 0 again, including batches waiting for retry.
+    // Epoch of transactional producer is bumped by its transaction coordinator, only when no transaction is in progress
+    fn bump_producer_epoch(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.is_transactional() {
+            let result = 
+                self.transaction_coordinator()
+                    .and_then(|coordinator_id| self.do_call_on_node::<InitProducerIdRequest, InitProducerIdResponse>(ApiKey::InitProducerIdKey, coordinator_id));
+
+            if result.is_err() {
+                self.forget_transaction_coordinator();
+            }
+
+            result?;
+        } else {
+            self.do_call::<InitProducerIdRequest, InitProducerIdResponse>(ApiKey::InitProducerIdKey)?;
+        }
+
+        let (lock, _) = &*self.accumulator;
+        lock.lock().unwrap().reset_sequences();
+
+        Ok(())
+    }
+
+    fn is_transactional(&self) -> bool {
+        self.state.configuration.transactional_id().ok().flatten().is_some()
+    }
+
+    // halves get new sequences when sent, so sequence of the partition goes back to the one of the rejected batch,
+    // which was not written (only the first batch of a partition is in flight)
+    fn split_batches(&mut self) {
+        if self.state.batches_to_split.is_empty() {
+            return;
+        }
+
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        for batch in self.state.batches_to_split.drain(..) {
+            if batch.base_sequence != -1 {
+                self.state.producer_sequences.insert((batch.topic_partition.topic.clone(), batch.topic_partition.partition), batch.base_sequence);
+            }
+
+            accumulator.split_and_reenqueue(batch);
+        }
+
+        condvar.notify_all();
+    }
+
+    fn retry_batches(&mut self) {
+        if self.state.batches_to_retry.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let (lock, condvar) = &*self.accumulator;
+        let mut accumulator = lock.lock().unwrap();
+
+        for (batch, error) in self.state.batches_to_retry.drain(..) {
+            if let Some(expired_error) = accumulator.reenqueue(batch, error, now) {
+                self.state.transaction_error = Some(expired_error);
+            }
+        }
+
+        if std::mem::take(&mut accumulator.sequence_lost) {
+            self.state.producer_epoch_bump_needed = true;
+        }
+
+        condvar.notify_all();
+    }
+
+    // batches of partitions which could not be added to the transaction are retried, the rest is sent
+    fn add_partitions_to_transaction(&mut self) {
+        let has_new_partitions = 
+            self.state.batches_to_send
+                .iter()
+                .any(|batch| !self.state.transaction_partitions.contains(&(batch.topic_partition.topic.clone(), batch.topic_partition.partition)));
+
+        if !has_new_partitions {
+            return;
+        }
+
+        let result = 
+            self.transaction_coordinator()
+                .and_then(|coordinator_id| self.do_call_on_node::<AddPartitionsToTxnRequest, AddPartitionsToTxnResponse>(ApiKey::AddPartitionsToTxnKey, coordinator_id));
+
+        if let Err(error) = result {
+            self.forget_transaction_coordinator();
+
+            let error = KafkaCallerError::new(&error.to_string());
+            let (added, not_added): (Vec<ProducerBatch>, Vec<ProducerBatch>) = 
+                self.state.batches_to_send
+                    .drain(..)
+                    .partition(|batch| self.state.transaction_partitions.contains(&(batch.topic_partition.topic.clone(), batch.topic_partition.partition)));
+
+            self.state.batches_to_send = added;
+            for batch in not_added {
+                self.state.batches_to_retry.push((batch, error.clone()));
+            }
+        }
+    }
+
+    fn run_transaction_command(&mut self, command: TransactionCommand) -> Result<(), Box<dyn Error>> {
+        match command {
+            TransactionCommand::Init => self.init_transactions(),
+            TransactionCommand::Commit => {
+                self.state.check_transaction_committable()?;
+                self.end_transaction(true)
+            },
+            TransactionCommand::Abort => {
+                self.end_transaction(false)?;
+
+                // sequences lost in the aborted transaction are reset only now, failed bump is tried again before next send
+                if self.state.producer_epoch_bump_allowed() {
+                    let _ = self.bump_producer_epoch();
+                }
+
+                Ok(())
+            },
+            TransactionCommand::SendOffsets { offsets, group_metadata } => {
+                // offsets may be partly added to the transaction, so it can only be aborted
+                let result = self.send_offsets_to_transaction(offsets, group_metadata);
+
+                if let Err(error) = &result {
+                    self.state.transaction_error = Some(KafkaCallerError::new(&format!("Sending offsets to transaction failed: {}", error)));
+                }
+
+                result
+            },
+        }
+    }
+
+    // AddOffsetsToTxn to transaction coordinator adds group offsets partition to the transaction,
+    // TxnOffsetCommit to group coordinator stores offsets which become visible when transaction commits
+    fn send_offsets_to_transaction(&mut self, offsets: HashMap<TopicPartition, i64>, group_metadata: ConsumerGroupMetadata) -> Result<(), Box<dyn Error>> {
+        let group_id = group_metadata.group_id.clone();
+        self.state.transaction_offsets = offsets;
+        self.state.transaction_group_metadata = Some(group_metadata);
+
+        let result = 
+            self.transaction_coordinator()
+                .and_then(|coordinator_id| self.do_call_on_node::<AddOffsetsToTxnRequest, AddOffsetsToTxnResponse>(ApiKey::AddOffsetsToTxnKey, coordinator_id));
+
+        if let Err(error) = result {
+            self.forget_transaction_coordinator();
+            return Err(error);
+        }
+
+        let group_coordinator_key = (GROUP_COORDINATOR, group_id.clone());
+
+        if !self.state.coordinators.contains_key(&group_coordinator_key) {
+            self.state.coordinator_key = Some((group_id.clone(), GROUP_COORDINATOR));
+            let result = self.do_call::<FindCoordinatorRequest, FindCoordinatorResponse>(ApiKey::FindCoordinatorKey);
+            self.state.coordinator_key = None;
+            result?;
+        }
+
+        let group_coordinator_id = 
+            self.state.coordinators
+                .get(&group_coordinator_key)
+                .ok_or(KafkaCallerError::new(&format!("No group coordinator found for group '{}'", group_id)))?
+                .node_id;
+
+        let result = self.do_call_on_node::<TxnOffsetCommitRequest, TxnOffsetCommitResponse>(ApiKey::TxnOffsetCommitKey, group_coordinator_id);
+
+        if result.is_err() {
+            if let Some(coordinator) = self.state.coordinators.remove(&group_coordinator_key) {
+                self.connections.remove(&coordinator.node_id);
+            }
+        }
+        self.state.transaction_offsets.clear();
+
+        result
+    }
+
+    // coordinator may be loading transactions or still completing transaction of previous producer with the same
+    // transactional id, so initialization is retried within request timeout
+    fn init_transactions(&mut self) -> Result<(), Box<dyn Error>> {
+        let started = Instant::now();
+        let request_timeout = Duration::from_millis(self.state.configuration.request_timeout_ms()? as u64);
+
+        loop {
+            let result = 
+                self.transaction_coordinator()
+                    .and_then(|coordinator_id| self.do_call_on_node::<InitProducerIdRequest, InitProducerIdResponse>(ApiKey::InitProducerIdKey, coordinator_id));
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(error) if started.elapsed() >= request_timeout => return Err(error),
+                Err(_) => {
+                    self.forget_transaction_coordinator();
+                    thread::sleep(RETRY_BACKOFF);
+                },
+            }
+        }
+    }
+
+    // transaction which did not add any partition or offsets is not known to coordinator, so there is nothing to end
+    fn end_transaction(&mut self, committed: bool) -> Result<(), Box<dyn Error>> {
+        if self.state.transaction_partitions.is_empty() && !self.state.transaction_offsets_added {
+            self.state.transaction_error = None;
+            return Ok(());
+        }
+
+        self.state.transaction_committed = committed;
+
+        let result = 
+            self.transaction_coordinator()
+                .and_then(|coordinator_id| self.do_call_on_node::<EndTxnRequest, EndTxnResponse>(ApiKey::EndTxnKey, coordinator_id));
+
+        if result.is_err() {
+            self.forget_transaction_coordinator();
+        }
+
+        result
+    }
+
+    // coordinator is looked up once and again after a request to it failed
+    fn transaction_coordinator(&mut self) -> Result<i32, Box<dyn Error>> {
+        let transactional_id = 
+            self.state.configuration
+                .transactional_id()?
+                .ok_or(KafkaCallerError::new("Transactions require transactional id in producer configuration"))?;
+
+        let transaction_coordinator_key = (TRANSACTION_COORDINATOR, transactional_id.clone());
+
+        if !self.state.coordinators.contains_key(&transaction_coordinator_key) {
+            self.do_call::<FindCoordinatorRequest, FindCoordinatorResponse>(ApiKey::FindCoordinatorKey)?;
+        }
+
+        Ok(
+            self.state.coordinators
+                .get(&transaction_coordinator_key)
+                .ok_or(KafkaCallerError::new(&format!("No transaction coordinator found for transactional id '{}'", transactional_id)))?
+                .node_id
+        )
+    }
+
+    fn forget_transaction_coordinator(&mut self) {
+        if let Ok(Some(transactional_id)) = self.state.configuration.transactional_id() {
+            if let Some(coordinator) = self.state.coordinators.remove(&(TRANSACTION_COORDINATOR, transactional_id)) {
+                self.connections.remove(&coordinator.node_id);
+            }
+        }
+    }
+
+    fn stop(&mut self, error: KafkaCallerError) {
+        let (lock, condvar) = &*self.accumulator;
+        lock.lock().unwrap().fail_all(error);
+        condvar.notify_all();
+    }
+
+    fn do_call<Req, Res>(&mut self, api_key: ApiKey) -> Result<(), Box<dyn Error>>
+        where
+            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        let ser_de: SerDe<Req, Res> = api_key.new_ser_de(Some(&self.state))?;
+
+        let request_body = Req::default().create_request(&self.state)?;
+
+        println!("{:#?}", request_body);
+
+        let call_result = self.io.call(
+            ser_de.serialize(
+                &self.state.configuration.client_id(),
+                self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+                request_body
+            )?
+        );
+
+        // response of timed out request may still arrive and would be read as response of the next one,
+        // so connection is replaced by a new one
+        let mut response_bytes = 
+            match call_result {
+                Ok(response_bytes) => response_bytes,
+                Err(error) => {
+                    // when broker is not reachable yet, the next failed call tries to reconnect again
+                    let _ = self.reconnect_bootstrap();
+
+                    return Err(error);
+                },
+            };
+
+        let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
+
+        println!("{:#?}", response_body);
+
+        response_body.process_response(&mut self.state)?;
+
+        Ok(())
+    }
+
+    fn do_call_on_node<Req, Res>(&mut self, api_key: ApiKey, node_id: i32) -> Result<(), Box<dyn Error>>
+        where
+            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        let (ser_de, request_bytes) = self.create_request_on_node::<Req, Res>(api_key, node_id)?;
+
+        // failed connection is dropped for the same reason as bootstrap one, next request opens a new one
+        let mut response_bytes = 
+            match self.node_io(node_id)?.call(request_bytes) {
+                Ok(response_bytes) => response_bytes,
+                Err(error) => {
+                    self.connections.remove(&node_id);
+                    return Err(error);
+                },
+            };
+
+        let (_, response_body) = ser_de.deserialize(&mut response_bytes)?;
+
+        println!("{:#?}", response_body);
+
+        response_body.process_response(&mut self.state)?;
+
+        Ok(())
+    }
+
+    // when broker cannot be reached, old connection is kept and the call fails again on next use
+    fn reconnect_bootstrap(&mut self) -> Result<(), Box<dyn Error>> {
+        let tcp_stream = TcpStream::connect(self.state.configuration.broker_address())?;
+
+        let io = IO::from(tcp_stream);
+        io.set_timeout(Duration::from_millis(self.state.configuration.request_timeout_ms()? as u64))?;
+        self.io = io;
+
+        Ok(())
+    }
+
+    // request is written without waiting for response
+    fn send_on_node<Req, Res>(&mut self, api_key: ApiKey, node_id: i32) -> Result<(), Box<dyn Error>>
+        where
+            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        let (_, request_bytes) = self.create_request_on_node::<Req, Res>(api_key, node_id)?;
+
+        self.node_io(node_id)?.send(request_bytes)?;
+
+        Ok(())
+    }
+
+    fn create_request_on_node<Req, Res>(&mut self, api_key: ApiKey, node_id: i32) -> Result<(SerDe<Req, Res>, BytesMut), Box<dyn Error>>
+        where
+            Req: Debug + Encodable + Decodable + Default + Message + HeaderVersion + CreateRequest<Req>,
+            Res: Debug + Encodable + Decodable + Default + Message + HeaderVersion + ProcessResponse<Res>
+    {
+        self.state.target_node_id = node_id;
+
+        let ser_de: SerDe<Req, Res> = api_key.new_ser_de(Some(&self.state))?;
+
+        let request_body = Req::default().create_request(&self.state)?;
+
+        println!("{:#?}", request_body);
+
+        let request_bytes =
+            ser_de.serialize(
+                &self.state.configuration.client_id(),
+                self.state.correlation_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+                request_body
+            )?;
+
+        Ok((ser_de, request_bytes))
+    }
+
+    fn node_io(&mut self, node_id: i32) -> Result<&mut IO, Box<dyn Error>> {
+        match self.connections.entry(node_id) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                // transaction coordinator does not have to be among brokers of fetched metadata
+                let (host, port) =
+                    match self.state.broker_metadata.brokers.get(&node_id) {
+                        Some(broker) => (broker.host.clone(), broker.port),
+                        None => 
+                            self.state.coordinators
+                                .values()
+                                .find(|coordinator| coordinator.node_id == node_id)
+                                .map(|coordinator| (coordinator.host.clone(), coordinator.port))
+                                .ok_or(KafkaCallerError::new(&format!("Could not find broker '{}' in stored metadata", node_id)))?,
+                    };
+                let tcp_stream = TcpStream::connect(format!("{}:{}", host, port))?;
+
+                let io = IO::from(tcp_stream);
+                io.set_timeout(Duration::from_millis(self.state.configuration.request_timeout_ms()? as u64))?;
+
+                Ok(entry.insert(io))
+            }
+        }
+    }
+}
+
+// batches of one node are sent in as many requests as needed to keep each under max request size,
+// batch which does not fit even alone is sent by itself and split when broker rejects it
+fn split_by_request_size(batches: Vec<ProducerBatch>, max_request_size: usize) -> Vec<Vec<ProducerBatch>> {
+    let mut requests: Vec<Vec<ProducerBatch>> = Vec::new();
+    let mut request_size = 0;
+
+    for batch in batches {
+        let batch_size = batch.size_bytes + batch.records.len() * MAX_RECORD_OVERHEAD + RECORD_BATCH_OVERHEAD;
+
+        match requests.last_mut() {
+            Some(request_batches) if request_size + batch_size <= max_request_size => {
+                request_size += batch_size;
+                request_batches.push(batch);
+            },
+            _ => {
+                request_size = batch_size;
+                requests.push(vec![batch]);
+            },
+        }
+    }
+
+    requests
+}
@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::marker::PhantomData;
+use bytes::Bytes;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+use crate::errors::KafkaCallerError;
+
+// Converts key or value of record to produce into bytes. Null keys and values are not passed to serializer.
+pub trait Serializer<T> {
+    fn serialize(&self, topic: &str, data: &T) -> Result<Bytes, Box<dyn Error>>;
+}
+
+// Converts key or value bytes of consumed record into application type. Null keys and values are not passed to deserializer.
+pub trait Deserializer<T> {
+    fn deserialize(&self, topic: &str, data: &Bytes) -> Result<T, Box<dyn Error>>;
+}
+
+// returns data as they are, used by consumer created without deserializers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytesDeserializer;
+
+impl Deserializer<Bytes> for BytesDeserializer {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<Bytes, Box<dyn Error>> {
+        Ok(data.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringDeserializer;
+
+impl Deserializer<String> for StringDeserializer {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<String, Box<dyn Error>> {
+        Ok(String::from_utf8(data.to_vec())?)
+    }
+}
+
+// big-endian, same as IntegerDeserializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I32Deserializer;
+
+impl Deserializer<i32> for I32Deserializer {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<i32, Box<dyn Error>> {
+        let bytes: [u8; 4] = 
+            data.as_ref()
+                .try_into()
+                .map_err(|_| KafkaCallerError::new(&format!("Size of data received by I32Deserializer is {} instead of 4", data.len())))?;
+
+        Ok(i32::from_be_bytes(bytes))
+    }
+}
+
+// big-endian, same as LongDeserializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I64Deserializer;
+
+impl Deserializer<i64> for I64Deserializer {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<i64, Box<dyn Error>> {
+        let bytes: [u8; 8] = 
+            data.as_ref()
+                .try_into()
+                .map_err(|_| KafkaCallerError::new(&format!("Size of data received by I64Deserializer is {} instead of 8", data.len())))?;
+
+        Ok(i64::from_be_bytes(bytes))
+    }
+}
+
+// uuid as UTF-8 string, same as UUIDDeserializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidDeserializer;
+
+impl Deserializer<Uuid> for UuidDeserializer {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<Uuid, Box<dyn Error>> {
+        Ok(Uuid::parse_str(std::str::from_utf8(data)?)?)
+    }
+}
+
+pub struct JsonDeserializer<T> {
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonDeserializer<T> {
+    pub fn new() -> Self {
+        Self {
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for JsonDeserializer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for JsonDeserializer<T> {
+    fn deserialize(&self, _topic: &str, data: &Bytes) -> Result<T, Box<dyn Error>> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+// puts data as they are, used by producer created without serializers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytesSerializer;
+
+impl Serializer<Bytes> for BytesSerializer {
+    fn serialize(&self, _topic: &str, data: &Bytes) -> Result<Bytes, Box<dyn Error>> {
+        Ok(data.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringSerializer;
+
+impl Serializer<String> for StringSerializer {
+    fn serialize(&self, _topic: &str, data: &String) -> Result<Bytes, Box<dyn Error>> {
+        Ok(Bytes::copy_from_slice(data.as_bytes()))
+    }
+}
+
+// big-endian, same as IntegerSerializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I32Serializer;
+
+impl Serializer<i32> for I32Serializer {
+    fn serialize(&self, _topic: &str, data: &i32) -> Result<Bytes, Box<dyn Error>> {
+        Ok(Bytes::copy_from_slice(&data.to_be_bytes()))
+    }
+}
+
+// big-endian, same as LongSerializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I64Serializer;
+
+impl Serializer<i64> for I64Serializer {
+    fn serialize(&self, _topic: &str, data: &i64) -> Result<Bytes, Box<dyn Error>> {
+        Ok(Bytes::copy_from_slice(&data.to_be_bytes()))
+    }
+}
+
+// uuid as UTF-8 string, same as UUIDSerializer of java client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidSerializer;
+
+impl Serializer<Uuid> for UuidSerializer {
+    fn serialize(&self, _topic: &str, data: &Uuid) -> Result<Bytes, Box<dyn Error>> {
+        Ok(Bytes::from(data.hyphenated().to_string()))
+    }
+}
+
+pub struct JsonSerializer<T> {
+    _type: PhantomData<fn(&T)>,
+}
+
+impl<T> JsonSerializer<T> {
+    pub fn new() -> Self {
+        Self {
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for JsonSerializer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Serialize> Serializer<T> for JsonSerializer<T> {
+    fn serialize(&self, _topic: &str, data: &T) -> Result<Bytes, Box<dyn Error>> {
+        Ok(Bytes::from(serde_json::to_vec(data)?))
+    }
+}
\ No newline at end of file
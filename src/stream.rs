@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use futures_core::Stream;
+use crate::{Consumer, ConsumerRecord, KafkaCallerError, RecordDeserializationError};
+
+type StreamItem<K, V> = Result<ConsumerRecord<K, V>, Box<dyn Error + Send + Sync>>;
+
+// Stream of consumed records. Consumer is blocking, so it is moved to its own thread which iterates it and passes records
+// through bounded channel, so consumer does not get ahead of stream by more than max poll records.
+// When stream is dropped, thread closes the consumer (commits records returned so far and leaves the group).
+// Thread checks the shutdown flag between polls, as it does not find out about dropped receiver while no records arrive.
+pub struct ConsumerStream<K, V> {
+    receiver: Receiver<StreamItem<K, V>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl<K, V> Consumer<K, V>
+    where
+        K: Send + 'static,
+        V: Send + 'static
+{
+    pub fn into_stream(mut self) -> Result<ConsumerStream<K, V>, Box<dyn Error>> {
+        let (sender, receiver) = mpsc::sync_channel::<StreamItem<K, V>>(self.state.configuration.max_poll_records()?);
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let thread_waker = waker.clone();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread_shutdown = shutdown.clone();
+
+        thread::spawn(move || {
+            'polling: while !thread_shutdown.load(Ordering::Acquire) {
+                let items: Vec<StreamItem<K, V>> = 
+                    match self.poll() {
+                        Ok(records) => 
+                            records
+                                .into_results()
+                                .into_iter()
+                                .map(|result| result.map_err(|error| -> Box<dyn Error + Send + Sync> { Box::new(error) }))
+                                .collect(),
+                        Err(error) => vec![Err(into_stream_error(error))],
+                    };
+
+                for item in items {
+                    if sender.send(item).is_err() {
+                        break 'polling;
+                    }
+
+                    if let Some(waker) = thread_waker.lock().unwrap().take() {
+                        waker.wake();
+                    }
+                }
+            }
+
+            // stream is dropped, so there is nobody to report failed close to, broker removes the member after session timeout
+            let _ = self.close();
+        });
+
+        Ok(
+            ConsumerStream {
+                receiver,
+                waker,
+                shutdown,
+            }
+        )
+    }
+}
+
+impl<K, V> Drop for ConsumerStream<K, V> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+    }
+}
+
+impl<K, V> Stream for ConsumerStream<K, V> {
+    type Item = StreamItem<K, V>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // lock is held until waker is stored, so consumer thread cannot send record in between and not wake the task
+        let mut waker = self.waker.lock().unwrap();
+
+        match self.receiver.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => {
+                *waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            },
+        }
+    }
+}
+
+// Errors of poll are not Send, errors of this crate and IO errors are passed on with their type, so they can be downcast
+// same as errors of poll, other errors are passed by their description.
+fn into_stream_error(error: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    let error = 
+        match error.downcast::<KafkaCallerError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+
+    let error = 
+        match error.downcast::<RecordDeserializationError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+
+    match error.downcast::<std::io::Error>() {
+        Ok(error) => error,
+        Err(error) => Box::new(KafkaCallerError::new(&error.to_string())),
+    }
+}
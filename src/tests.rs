@@ -1,13 +1,82 @@
 #[cfg(test)]
-use crate::{Configuration, Consumer, Producer, io::records::PutRecord};
+use crate::{Configuration, Consumer, Producer, Acks, CompressionType, OffsetResetPolicy, IsolationLevel, PutRecord, StringDeserializer, StringSerializer};
+#[cfg(test)]
+use crate::partitioner::{murmur2, partition_for_key};
+#[cfg(test)]
+use crate::{Partitioner, StickyPartitioner, RoundRobinPartitioner};
+#[cfg(test)]
+use bytes::Bytes;
+#[cfg(test)]
+use crate::io::accumulator::{RecordAccumulator, increment_sequence, MAX_RECORD_OVERHEAD, RECORD_BATCH_OVERHEAD};
+#[cfg(test)]
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use bytes::BytesMut;
+#[cfg(test)]
+use kafka_protocol::records::RecordEncodeOptions;
+
+#[cfg(test)]
+use crate::io::record_batch::{Record, RecordBatchDecoder, RecordBatchEncoder};
+#[cfg(test)]
+use crate::{ConsumerRecord, KafkaCallerError, ProducerInterceptor, RecordMetadata, Headers};
+#[cfg(test)]
+use crate::io::accumulator::AcknowledgementHook;
+#[cfg(test)]
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use uuid::Uuid;
+#[cfg(test)]
+use kafka_protocol::messages::{FetchRequest, FetchResponse, fetch_response::{FetchableTopicResponse, PartitionData, EpochEndOffset}};
+#[cfg(test)]
+use crate::io::messages::fetch::ProcessFetchResponse;
+#[cfg(test)]
+use kafka_protocol::messages::{OffsetForLeaderEpochResponse, TopicName, offset_for_leader_epoch_response::{OffsetForLeaderTopicResult, EpochEndOffset as LeaderEpochEndOffset}};
+#[cfg(test)]
+use crate::io::messages::ProcessResponse;
+#[cfg(test)]
+use crate::utils::to_kafka_str;
+#[cfg(test)]
+use crate::io::call_state::{CallState, Broker, Topic, Partition, PartitionOffsetState, FetchSession};
+#[cfg(test)]
+use crate::io::messages::CreateRequest;
+#[cfg(test)]
+use crate::io::call_state::{GROUP_COORDINATOR, TRANSACTION_COORDINATOR};
+#[cfg(test)]
+use kafka_protocol::messages::{BrokerId, FindCoordinatorResponse, find_coordinator_response::Coordinator as ResponseCoordinator};
+#[cfg(test)]
+use kafka_protocol::messages::HeartbeatResponse;
+#[cfg(test)]
+use kafka_protocol::messages::{OffsetCommitResponse, offset_commit_response::{OffsetCommitResponseTopic, OffsetCommitResponsePartition}};
+#[cfg(test)]
+use kafka_protocol::messages::{ProducerId, fetch_response::AbortedTransaction};
+#[cfg(test)]
+use crate::io::messages::fetch::{filter_records, is_abort_marker};
+#[cfg(test)]
+use kafka_protocol::messages::{ListOffsetsResponse, list_offsets_response::{ListOffsetsTopicResponse, ListOffsetsPartitionResponse}};
+#[cfg(test)]
+use kafka_protocol::messages::{ProduceResponse, produce_response::{TopicProduceResponse, PartitionProduceResponse, BatchIndexAndErrorMessage}};
+#[cfg(test)]
+use crate::io::accumulator::{ProducerBatch, SendHandle};
+#[cfg(test)]
+use kafka_protocol::messages::{InitProducerIdResponse, EndTxnResponse, ProducerId};
+#[cfg(test)]
+use indexmap::IndexMap;
+#[cfg(test)]
+use crate::heartbeat::{Heartbeat, HeartbeatState, MembershipLoss, SharedHeartbeat};
+#[cfg(test)]
+use crate::io::call_state::GroupSubscription;
+#[cfg(test)]
+use std::sync::{Mutex, Condvar};
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use crate::TopicPartition;
 
 #[test]
 pub fn test_poll() {
-    let configuration = Configuration::ConsumerConfiguration {
-        broker_address: String::from("127.0.0.1:9092"),
-        client_id: String::from("test-client-rs"),
-        group_id: String::from("test-client-rs.group"),
-    };
+    let configuration = test_consumer_configuration();
 
     let mut consumer = Consumer::new(&configuration).unwrap();
     consumer.subscribe(vec!("test_topic"));
@@ -15,12 +84,20 @@ pub fn test_poll() {
     println!("{:#?}", records)
 }
 
+#[test]
+pub fn test_poll_with_deserializers() {
+    let configuration = test_consumer_configuration();
+
+    let mut consumer = Consumer::with_deserializers(&configuration, StringDeserializer, StringDeserializer).unwrap();
+    consumer.subscribe(vec!("test_topic"));
+    let records = consumer.first_poll().unwrap();
+    println!("{:#?}", records);
+    println!("{:#?}", records.deserialization_errors())
+}
+
 #[test]
 pub fn test_put() {
-    let configuration = Configuration::ProducerConfiguration {
-        broker_address: String::from("127.0.0.1:9092"),
-        client_id: String::from("test-client-rs"),
-    };
+    let configuration = test_producer_configuration();
 
     let mut producer = Producer::new(&configuration).unwrap();
 
@@ -32,4 +109,1332 @@ pub fn test_put() {
                 PutRecord::new_with_key_value_str("test_topic", "WOHOO_3", "It works !!")
             ]
         ).unwrap();
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_put_with_serializers() {
+    let configuration = test_producer_configuration();
+
+    let mut producer = Producer::with_serializers(&configuration, StringSerializer, StringSerializer).unwrap();
+
+    producer
+        .put(&mut 
+            vec![
+                PutRecord::with_key_value("test_topic", Some(String::from("WOHOO_4")), Some(String::from("It works typed !")))
+            ]
+        ).unwrap();
+}
+
+#[test]
+pub fn test_murmur2_matches_java_client() {
+    assert_eq!(murmur2("21".as_bytes()), -973932308);
+    assert_eq!(murmur2("foobar".as_bytes()), -790332482);
+    assert_eq!(murmur2("a-little-bit-long-string".as_bytes()), -985981536);
+    assert_eq!(murmur2("a-little-bit-longer-string".as_bytes()), -1486304829);
+    assert_eq!(murmur2("lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8".as_bytes()), -58897971);
+    assert_eq!(murmur2("abc".as_bytes()), 479470107);
+
+    assert_eq!(partition_for_key("foobar".as_bytes(), 3), (-790332482 & 0x7fffffff) % 3);
+}
+
+#[test]
+pub fn test_sticky_and_round_robin_partitioners() {
+    let value = Bytes::from_static(&[0u8; 100]);
+    let partitions = [0, 1, 2];
+
+    let mut sticky_partitioner = StickyPartitioner::with_batch_size(1000);
+    let first = sticky_partitioner.partition("test_topic", None, Some(&value), &partitions, &partitions);
+    for _ in 0..9 {
+        assert_eq!(sticky_partitioner.partition("test_topic", None, Some(&value), &partitions, &partitions), first);
+    }
+
+    let mut round_robin_partitioner = RoundRobinPartitioner::new();
+    let assigned: Vec<i32> = 
+        (0..6)
+            .map(|_| round_robin_partitioner.partition("test_topic", None, Some(&value), &partitions, &[0, 2]))
+            .collect();
+    assert_eq!(assigned, vec![0, 2, 0, 2, 0, 2]);
+}
+
+#[test]
+pub fn test_record_accumulator_batching() {
+    let mut accumulator = RecordAccumulator::new(25, 1000, 100, 120000);
+
+    for _ in 0..3 {
+        let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+        record.set_partition(0);
+        accumulator.append(record);
+    }
+
+    // first batch is full (22 bytes + 11 would exceed 25), second one is still lingering
+    let ready = accumulator.drain_ready(Instant::now());
+    assert_eq!(ready.len(), 1);
+    assert_eq!(ready[0].records.len(), 2);
+    assert!(accumulator.drain_ready(Instant::now()).is_empty());
+
+    let ready = accumulator.drain_ready(Instant::now() + Duration::from_millis(1000));
+    assert_eq!(ready.len(), 1);
+    assert!(accumulator.is_empty());
+}
+
+#[test]
+pub fn test_record_accumulator_splits_rejected_batch() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 120000);
+
+    for _ in 0..5 {
+        let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+        record.set_partition(0);
+        accumulator.append(record);
+    }
+
+    let mut ready = accumulator.drain_ready(Instant::now());
+    assert_eq!(ready.len(), 1);
+    accumulator.split_and_reenqueue(ready.remove(0));
+
+    // halves are sent one after another to keep order of records in partition
+    let first = accumulator.drain_ready(Instant::now());
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].records.len(), 2);
+    assert_eq!(first[0].size_bytes, 22);
+
+    let second = accumulator.drain_ready(Instant::now());
+    assert_eq!(second.len(), 1);
+    assert_eq!(second[0].records.len(), 3);
+    assert_eq!(second[0].size_bytes, 33);
+    assert!(accumulator.is_empty());
+}
+
+#[test]
+pub fn test_record_timestamp_is_kept_by_accumulator() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 120000);
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "business-event");
+    record.set_partition(0);
+    record.set_timestamp(1500000000000);
+    accumulator.append(record);
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "now");
+    record.set_partition(0);
+    accumulator.append(record);
+
+    let ready = accumulator.drain_ready(Instant::now());
+    assert_eq!(ready[0].timestamps[0], 1500000000000);
+    assert!(ready[0].timestamps[1] > 1500000000000);
+}
+
+#[test]
+pub fn test_record_accumulator_is_done_after_in_flight_batches() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 120000);
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    record.set_partition(0);
+    let handle = accumulator.append(record);
+
+    let ready = accumulator.drain_ready(Instant::now());
+    accumulator.in_flight_batches += ready.len();
+    assert!(accumulator.is_empty());
+    assert!(!accumulator.is_done());
+
+    ready.into_iter().for_each(|batch| batch.complete(10, -1));
+    accumulator.in_flight_batches = 0;
+    assert!(accumulator.is_done());
+    assert_eq!(handle.wait().unwrap().offset, 10);
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    record.set_partition(1);
+    let handle = accumulator.append(record);
+    assert_eq!(accumulator.abort_batches(KafkaCallerError::new("closed")), 1);
+    assert!(handle.wait().is_err());
+}
+
+#[cfg(test)]
+struct TracingInterceptor {
+    acknowledged: AtomicUsize,
+}
+
+#[cfg(test)]
+impl ProducerInterceptor for TracingInterceptor {
+    fn on_send(&self, record: &mut PutRecord) {
+        record.add_header_with_str_key("trace-id", b"1234");
+    }
+
+    fn on_acknowledgement(&self, result: Result<&RecordMetadata, &KafkaCallerError>) {
+        if result.is_ok() {
+            self.acknowledged.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[test]
+pub fn test_producer_interceptor_stamps_and_observes_records() {
+    let interceptor = Arc::new(TracingInterceptor { acknowledged: AtomicUsize::new(0) });
+    let hook_interceptor = interceptor.clone();
+
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 120000);
+    accumulator.acknowledgement_hook = 
+        Some(AcknowledgementHook(Arc::new(move |result: &Result<RecordMetadata, KafkaCallerError>| hook_interceptor.on_acknowledgement(result.as_ref()))));
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    record.set_partition(0);
+    interceptor.on_send(&mut record);
+    assert_eq!(record.headers.last_header("trace-id").and_then(|header| header.value_str()), Some("1234"));
+
+    let handle = accumulator.append(record);
+    accumulator.drain_ready(Instant::now()).into_iter().for_each(|batch| batch.complete(0, -1));
+
+    assert_eq!(interceptor.acknowledged.load(Ordering::SeqCst), 1);
+    assert!(handle.wait().is_ok());
+}
+
+#[test]
+pub fn test_headers_keep_order_and_repeated_keys() {
+    let mut headers = Headers::new();
+    headers.add_str("trace", "first");
+    headers.add_bytes("span", &[1, 2]);
+    headers.add_str("trace", "second");
+    headers.add("baggage", None);
+
+    assert_eq!(headers.len(), 4);
+    assert_eq!(headers.iter().map(|header| header.key.as_str()).collect::<Vec<&str>>(), vec!["trace", "span", "trace", "baggage"]);
+    assert_eq!(headers.headers("trace").filter_map(|header| header.value_str()).collect::<Vec<&str>>(), vec!["first", "second"]);
+    assert_eq!(headers.last_header("trace").and_then(|header| header.value_str()), Some("second"));
+    assert_eq!(headers.last_header("baggage").map(|header| header.value.is_none()), Some(true));
+    assert!(headers.last_header("missing").is_none());
+
+    headers.remove("trace");
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers.headers("trace").count(), 0);
+}
+
+#[test]
+pub fn test_tombstone_null_key_and_empty_value_survive_encoding() {
+    let tombstone: PutRecord = PutRecord::tombstone("test_topic", Bytes::from("deleted-key"));
+    let keyless: PutRecord = PutRecord::with_value("test_topic", Bytes::from("value"));
+    let empty_value: PutRecord = PutRecord::with_key_value("test_topic", Some(Bytes::from("key")), Some(Bytes::new()));
+    assert!(tombstone.is_tombstone());
+    assert!(!empty_value.is_tombstone());
+
+    let records: Vec<Record> = 
+        [tombstone, keyless, empty_value]
+            .iter()
+            .enumerate()
+            .map(|(index, put_record)| {
+                let mut record: Record = put_record.into();
+                record.offset = index as i64;
+                record.timestamp = 1700000000000;
+                record
+            })
+            .collect();
+
+    let encode_options = 
+        RecordEncodeOptions {
+            compression: CompressionType::None.into(),
+            version: 2,
+        };
+
+    let mut record_bytes = BytesMut::default();
+    RecordBatchEncoder::encode(&mut record_bytes, records.iter(), &encode_options).unwrap();
+
+    let consumer_records: Vec<ConsumerRecord<String, String>> = 
+        RecordBatchDecoder::decode(&mut record_bytes.freeze())
+            .unwrap()
+            .into_iter()
+            .map(|record| ConsumerRecord::from_record("test_topic", 0, record).deserialize(&StringDeserializer, &StringDeserializer).unwrap())
+            .collect();
+
+    assert_eq!(consumer_records[0].key, Some(String::from("deleted-key")));
+    assert!(consumer_records[0].is_tombstone());
+
+    assert_eq!(consumer_records[1].key, None);
+    assert_eq!(consumer_records[1].value, Some(String::from("value")));
+
+    assert_eq!(consumer_records[2].value, Some(String::new()));
+    assert!(!consumer_records[2].is_tombstone());
+}
+
+#[test]
+pub fn test_increment_sequence_wraps_like_java_client() {
+    assert_eq!(increment_sequence(0, 5), 5);
+    assert_eq!(increment_sequence(i32::MAX - 2, 2), i32::MAX);
+    assert_eq!(increment_sequence(i32::MAX - 2, 3), 0);
+    assert_eq!(increment_sequence(i32::MAX, 5), 4);
+}
+
+#[test]
+pub fn test_compression_level_is_used_by_codec() {
+    let records: Vec<Record> = 
+        (0..100)
+            .map(|index| {
+                let mut record: Record = (&PutRecord::new_with_key_value_str("test_topic", "key", &"value".repeat(20))).into();
+                record.offset = index;
+                record
+            })
+            .collect();
+
+    for (compression_type, fastest, strongest) in [(CompressionType::Gzip, 0, 9), (CompressionType::Lz4, 0, 16), (CompressionType::Zstd, 1, 19)] {
+        let encode_options = 
+            RecordEncodeOptions {
+                compression: compression_type.into(),
+                version: 2,
+            };
+
+        let mut fastest_bytes = BytesMut::default();
+        RecordBatchEncoder::encode_with_level(&mut fastest_bytes, records.iter(), &encode_options, Some(fastest)).unwrap();
+        let mut strongest_bytes = BytesMut::default();
+        RecordBatchEncoder::encode_with_level(&mut strongest_bytes, records.iter(), &encode_options, Some(strongest)).unwrap();
+
+        // level 0 of gzip only stores the data
+        if compression_type == CompressionType::Gzip {
+            assert!(strongest_bytes.len() < fastest_bytes.len());
+        }
+
+        for record_bytes in [fastest_bytes, strongest_bytes] {
+            let decoded_records = RecordBatchDecoder::decode(&mut record_bytes.freeze()).unwrap();
+            assert_eq!(test_record_offsets(&decoded_records), test_record_offsets(&records), "{:?}", compression_type);
+            assert_eq!(decoded_records[99].value, records[99].value, "{:?}", compression_type);
+        }
+    }
+
+    assert!(CompressionType::Gzip.check_level(9).is_ok());
+    assert!(CompressionType::Gzip.check_level(10).is_err());
+    assert!(CompressionType::Zstd.check_level(22).is_ok());
+    assert!(CompressionType::Snappy.check_level(1).is_err());
+}
+
+#[test]
+pub fn test_consumer_decodes_all_compression_types() {
+    for compression_type in [CompressionType::None, CompressionType::Gzip, CompressionType::Snappy, CompressionType::Lz4, CompressionType::Zstd] {
+        let records: Vec<Record> = 
+            (0..3)
+                .map(|index| {
+                    let mut record: Record = (&PutRecord::new_with_key_value_str("test_topic", &format!("key-{}", index), "value")).into();
+                    record.offset = index;
+                    record.timestamp = 1700000000000;
+                    record
+                })
+                .collect();
+
+        let encode_options = 
+            RecordEncodeOptions {
+                compression: compression_type.into(),
+                version: 2,
+            };
+
+        let mut record_bytes = BytesMut::default();
+        RecordBatchEncoder::encode(&mut record_bytes, records.iter(), &encode_options).unwrap();
+
+        let consumer_records: Vec<ConsumerRecord> = 
+            RecordBatchDecoder::decode(&mut record_bytes.freeze())
+                .unwrap()
+                .into_iter()
+                .map(|record| ConsumerRecord::from_record("test_topic", 0, record))
+                .collect();
+
+        assert_eq!(consumer_records.len(), 3, "{:?}", compression_type);
+        for (index, consumer_record) in consumer_records.iter().enumerate() {
+            assert_eq!(consumer_record.offset, index as i64);
+            assert_eq!(consumer_record.key, Some(Bytes::from(format!("key-{}", index))));
+            assert_eq!(consumer_record.value, Some(Bytes::from("value")));
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_consumer_configuration() -> Configuration {
+    Configuration::ConsumerConfiguration {
+        broker_address: String::from("127.0.0.1:9092"),
+        client_id: String::from("test-client-rs"),
+        group_id: String::from("test-client-rs.group"),
+        auto_offset_reset: OffsetResetPolicy::Earliest,
+        client_rack: None,
+        isolation_level: IsolationLevel::ReadUncommitted,
+        max_poll_records: 500,
+        enable_auto_commit: true,
+        max_poll_interval_ms: 300000,
+        session_timeout_ms: 45000,
+        fetch_min_bytes: 1,
+        fetch_max_bytes: 52428800,
+        fetch_max_wait_ms: 500,
+        max_partition_fetch_bytes: 1048576,
+    }
+}
+
+#[cfg(test)]
+fn test_producer_configuration() -> Configuration {
+    Configuration::ProducerConfiguration {
+        broker_address: String::from("127.0.0.1:9092"),
+        client_id: String::from("test-client-rs"),
+        batch_size: 16384,
+        linger_ms: 5,
+        buffer_memory: 33554432,
+        acks: Acks::All,
+        request_timeout_ms: 30000,
+        delivery_timeout_ms: 120000,
+        enable_idempotence: true,
+        transactional_id: None,
+        transaction_timeout_ms: 60000,
+        compression_type: CompressionType::Snappy,
+        compression_level: None,
+        max_request_size: 1048576,
+    }
+}
+
+// consumer state fetching partitions 0, 1 and 2 of test_topic from broker 1, all with committed offset 10
+#[cfg(test)]
+fn test_fetch_state() -> CallState {
+    let mut state = CallState::new(&test_consumer_configuration()).unwrap();
+
+    state.broker_metadata.brokers.insert(1, Broker { id: 1, host: String::from("127.0.0.1"), port: 9092, rack: None });
+    state.broker_metadata.topics.insert(
+        String::from("test_topic"),
+        Topic {
+            id: Uuid::from_u128(1),
+            name: String::from("test_topic"),
+            partitions: 
+                (0..3)
+                    .map(|index| (index, Partition { index, leader_id: 1, leader_epoch: 0, replica_ids: vec![1], isr_ids: vec![1] }))
+                    .collect(),
+        }
+    );
+    state.fetch_state.insert(String::from("test_topic"), (0..3).map(|index| (index, PartitionOffsetState::new(10))).collect());
+    state.target_node_id = 1;
+
+    state
+}
+
+#[test]
+pub fn test_fetch_session_update() {
+    let sent_partitions: HashMap<(String, i32), i64> = HashMap::from([((String::from("test_topic"), 0), 10)]);
+    let mut fetch_session = FetchSession::default();
+    assert!(!fetch_session.is_incremental());
+
+    // broker created session
+    fetch_session.update(5, sent_partitions.clone());
+    assert_eq!((fetch_session.id, fetch_session.epoch), (5, 1));
+    assert!(fetch_session.is_incremental());
+    assert_eq!(fetch_session.partitions, sent_partitions);
+
+    fetch_session.update(5, sent_partitions.clone());
+    assert_eq!((fetch_session.id, fetch_session.epoch), (5, 2));
+
+    // broker replaced session with a new one
+    fetch_session.update(7, sent_partitions.clone());
+    assert_eq!((fetch_session.id, fetch_session.epoch), (7, 1));
+
+    // epoch 0 is reserved for session creation, so it wraps to 1
+    fetch_session.epoch = i32::MAX;
+    fetch_session.update(7, sent_partitions.clone());
+    assert_eq!((fetch_session.id, fetch_session.epoch), (7, 1));
+
+    // broker did not keep the session, next fetch is a full one
+    fetch_session.update(0, sent_partitions);
+    assert_eq!((fetch_session.id, fetch_session.epoch), (0, 0));
+    assert!(fetch_session.partitions.is_empty());
+}
+
+#[test]
+pub fn test_full_fetch_request_without_session() {
+    let state = test_fetch_state();
+
+    let request = FetchRequest::default().create_request(&state).unwrap();
+
+    assert_eq!((request.session_id, request.session_epoch), (0, 0));
+    assert_eq!(request.topics.len(), 1);
+    let mut partitions: Vec<(i32, i64)> = request.topics[0].partitions.iter().map(|partition| (partition.partition, partition.fetch_offset)).collect();
+    partitions.sort();
+    assert_eq!(partitions, vec![(0, 10), (1, 10), (2, 10)]);
+    assert!(request.forgotten_topics_data.is_empty());
+}
+
+#[test]
+pub fn test_incremental_fetch_request_sends_changed_and_forgotten_partitions() {
+    let mut state = test_fetch_state();
+    state.fetch_state.get_mut("test_topic").unwrap().get_mut(&1).unwrap().polled_offset = 14;
+
+    // session holds partition 0 unchanged, partition 1 at older offset, partition 2 is missing and partition 3 is no longer fetched
+    state.fetch_sessions.insert(
+        1, 
+        FetchSession {
+            id: 5,
+            epoch: 3,
+            partitions: HashMap::from([
+                ((String::from("test_topic"), 0), 10),
+                ((String::from("test_topic"), 1), 10),
+                ((String::from("test_topic"), 3), 10),
+            ]),
+        }
+    );
+
+    let request = FetchRequest::default().create_request(&state).unwrap();
+
+    assert_eq!((request.session_id, request.session_epoch), (5, 3));
+    let mut partitions: Vec<(i32, i64)> = request.topics[0].partitions.iter().map(|partition| (partition.partition, partition.fetch_offset)).collect();
+    partitions.sort();
+    assert_eq!(partitions, vec![(1, 15), (2, 10)]);
+
+    assert_eq!(request.forgotten_topics_data.len(), 1);
+    assert_eq!(&*request.forgotten_topics_data[0].topic.0, "test_topic");
+    assert_eq!(request.forgotten_topics_data[0].partitions, vec![3]);
+}
+
+#[cfg(test)]
+fn test_fetch_response(partitions: Vec<PartitionData>) -> FetchResponse {
+    FetchResponse {
+        error_code: 0,
+        session_id: 0,
+        responses: vec![
+            FetchableTopicResponse {
+                topic_id: Uuid::from_u128(1),
+                partitions,
+                ..Default::default()
+            }
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+pub fn test_fetch_partition_errors() {
+    let mut state = test_fetch_state();
+    state.fetch_state.get_mut("test_topic").unwrap().get_mut(&2).unwrap().preferred_read_replica = 2;
+
+    let response = 
+        test_fetch_response(vec![
+            // OFFSET_OUT_OF_RANGE with reset policy
+            PartitionData { partition_index: 0, error_code: 1, ..Default::default() },
+            // CORRUPT_MESSAGE
+            PartitionData { partition_index: 1, error_code: 2, ..Default::default() },
+            // NOT_LEADER_OR_FOLLOWER
+            PartitionData { partition_index: 2, error_code: 6, ..Default::default() },
+        ]);
+
+    let records = response.process_response(&mut state).unwrap();
+    assert!(records.is_empty());
+
+    let partitions = state.fetch_state.get("test_topic").unwrap();
+    assert!(partitions[&0].offset_reset_needed);
+    assert!(!partitions[&1].offset_reset_needed);
+    assert_eq!(partitions[&2].preferred_read_replica, -1);
+    assert!(state.metadata_refresh_needed);
+
+    // corrupt message fails the next poll, leader change and offset reset do not
+    let fetch_error = state.pending_fetch_error.take().unwrap();
+    assert!(fetch_error.0.contains("corrupt message for partition 'test_topic-1'"));
+    assert!(!fetch_error.0.contains("test_topic-0"));
+    assert!(!fetch_error.0.contains("test_topic-2"));
+}
+
+#[test]
+pub fn test_fetch_errors_without_reset_policy_and_unknown_codes() {
+    let mut state = test_fetch_state();
+    if let Configuration::ConsumerConfiguration { auto_offset_reset, .. } = &mut state.configuration {
+        *auto_offset_reset = OffsetResetPolicy::None;
+    }
+
+    let response = 
+        test_fetch_response(vec![
+            PartitionData { partition_index: 0, error_code: 1, ..Default::default() },
+            // UNKNOWN_LEADER_EPOCH
+            PartitionData { partition_index: 1, error_code: 75, ..Default::default() },
+            // UNKNOWN_SERVER_ERROR
+            PartitionData { partition_index: 2, error_code: -1, ..Default::default() },
+        ]);
+
+    response.process_response(&mut state).unwrap();
+
+    let partitions = state.fetch_state.get("test_topic").unwrap();
+    assert!(!partitions[&0].offset_reset_needed);
+    assert!(state.metadata_refresh_needed);
+
+    let fetch_error = state.pending_fetch_error.take().unwrap();
+    assert!(fetch_error.0.contains("out of range for partition 'test_topic-0'"));
+    assert!(fetch_error.0.contains("error code '-1' for partition 'test_topic-2'"));
+    assert!(!fetch_error.0.contains("test_topic-1"));
+}
+
+#[test]
+pub fn test_fetch_session_errors_reset_session() {
+    let mut state = test_fetch_state();
+    state.fetch_sessions.insert(1, FetchSession { id: 5, epoch: 3, partitions: HashMap::new() });
+
+    // FETCH_SESSION_ID_NOT_FOUND
+    let response = FetchResponse { error_code: 70, ..Default::default() };
+    assert!(response.process_response(&mut state).unwrap().is_empty());
+    assert!(!state.fetch_sessions.contains_key(&1));
+
+    // any other top level error fails the fetch
+    let response = FetchResponse { error_code: -1, ..Default::default() };
+    assert!(response.process_response(&mut state).is_err());
+}
+
+#[test]
+pub fn test_partition_truncation_moves_position_back() {
+    let mut offset_state = PartitionOffsetState::new(10);
+    offset_state.polled_offset = 19;
+    offset_state.consumed_offset = 17;
+
+    offset_state.truncate(15, 3);
+    assert_eq!(offset_state.fetch_offset(), 15);
+    assert_eq!(offset_state.last_fetched_epoch, 3);
+    // records after end offset were returned to application but are lost, so they are not committed
+    assert_eq!((offset_state.consumed_offset, offset_state.consumed_leader_epoch), (14, 3));
+
+    // consumed position before end offset is kept
+    let mut offset_state = PartitionOffsetState::new(10);
+    offset_state.polled_offset = 19;
+    offset_state.consumed_offset = 12;
+    offset_state.consumed_leader_epoch = 2;
+    offset_state.truncate(15, 3);
+    assert_eq!((offset_state.consumed_offset, offset_state.consumed_leader_epoch), (12, 2));
+}
+
+#[test]
+pub fn test_fetch_diverging_epoch_truncates_partition() {
+    let mut state = test_fetch_state();
+    state.fetch_state.get_mut("test_topic").unwrap().get_mut(&0).unwrap().polled_offset = 19;
+
+    let response = 
+        test_fetch_response(vec![
+            PartitionData { 
+                partition_index: 0, 
+                diverging_epoch: EpochEndOffset { epoch: 3, end_offset: 15, ..Default::default() }, 
+                ..Default::default() 
+            },
+        ]);
+
+    response.process_response(&mut state).unwrap();
+
+    assert_eq!(state.fetch_state["test_topic"][&0].fetch_offset(), 15);
+    assert!(state.pending_fetch_error.take().unwrap().0.contains("Log truncation detected for partition 'test_topic-0'"));
+}
+
+#[test]
+pub fn test_offset_for_leader_epoch_validates_positions() {
+    let mut state = test_fetch_state();
+    for offset_state in state.fetch_state.get_mut("test_topic").unwrap().values_mut() {
+        offset_state.polled_offset = 19;
+        offset_state.last_fetched_epoch = 2;
+        offset_state.position_validation_needed = true;
+    }
+
+    let response = 
+        OffsetForLeaderEpochResponse {
+            topics: vec![
+                OffsetForLeaderTopicResult {
+                    topic: TopicName(to_kafka_str("test_topic")),
+                    partitions: vec![
+                        // epoch ended before fetch position, records after it were lost
+                        LeaderEpochEndOffset { partition: 0, error_code: 0, leader_epoch: 2, end_offset: 15, ..Default::default() },
+                        // leader does not know the epoch
+                        LeaderEpochEndOffset { partition: 1, error_code: 0, leader_epoch: -1, end_offset: -1, ..Default::default() },
+                        // NOT_LEADER_OR_FOLLOWER
+                        LeaderEpochEndOffset { partition: 2, error_code: 6, leader_epoch: -1, end_offset: -1, ..Default::default() },
+                    ],
+                    ..Default::default()
+                }
+            ],
+            ..Default::default()
+        };
+
+    response.process_response(&mut state).unwrap();
+
+    let partitions = &state.fetch_state["test_topic"];
+    assert_eq!(partitions[&0].fetch_offset(), 15);
+    assert!(!partitions[&0].position_validation_needed);
+    assert!(partitions[&1].offset_reset_needed);
+    assert!(partitions[&2].position_validation_needed);
+    assert!(state.metadata_refresh_needed);
+
+    let fetch_error = state.pending_fetch_error.take().unwrap();
+    assert!(fetch_error.0.contains("test_topic-0"));
+    assert!(fetch_error.0.contains("test_topic-1"));
+    assert!(!fetch_error.0.contains("test_topic-2"));
+}
+
+#[cfg(test)]
+fn test_list_offsets_response(partitions: Vec<ListOffsetsPartitionResponse>) -> ListOffsetsResponse {
+    ListOffsetsResponse {
+        topics: vec![
+            ListOffsetsTopicResponse {
+                name: TopicName(to_kafka_str("test_topic")),
+                partitions,
+                ..Default::default()
+            }
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+pub fn test_list_offsets_retries_partition_after_stale_metadata() {
+    let mut state = test_fetch_state();
+
+    test_list_offsets_response(vec![
+        // NOT_LEADER_OR_FOLLOWER
+        ListOffsetsPartitionResponse { partition_index: 0, error_code: 6, offset: -1, ..Default::default() },
+        ListOffsetsPartitionResponse { partition_index: 1, error_code: 0, offset: 20, ..Default::default() },
+    ]).process_response(&mut state).unwrap();
+
+    assert!(state.metadata_refresh_needed);
+    assert!(state.offset_listing_needed());
+    assert_eq!(state.fetch_state["test_topic"][&1].commited_offset, 20);
+
+    // partition is not fetched from stale position until its offsets are listed again
+    let request = FetchRequest::default().create_request(&state).unwrap();
+    let mut partitions: Vec<i32> = request.topics[0].partitions.iter().map(|partition| partition.partition).collect();
+    partitions.sort();
+    assert_eq!(partitions, vec![1, 2]);
+
+    test_list_offsets_response(vec![
+        ListOffsetsPartitionResponse { partition_index: 0, error_code: 0, offset: 30, ..Default::default() },
+    ]).process_response(&mut state).unwrap();
+
+    assert!(!state.offset_listing_needed());
+    assert_eq!(state.fetch_state["test_topic"][&0].commited_offset, 30);
+
+    // other error codes still fail the call
+    let result = 
+        test_list_offsets_response(vec![
+            ListOffsetsPartitionResponse { partition_index: 0, error_code: 35, offset: -1, ..Default::default() },
+        ]).process_response(&mut state);
+    assert!(result.is_err());
+}
+
+#[cfg(test)]
+fn test_transactional_record(offset: i64, producer_id: i64) -> Record {
+    let mut record: Record = (&PutRecord::new_with_key_value_str("test_topic", &format!("key-{}", offset), "value")).into();
+    record.offset = offset;
+    record.producer_id = producer_id;
+    record.transactional = true;
+
+    record
+}
+
+#[cfg(test)]
+fn test_control_record(offset: i64, producer_id: i64, abort: bool) -> Record {
+    let mut record = test_transactional_record(offset, producer_id);
+    record.control = true;
+    record.key = Some(Bytes::from(vec![0, 0, 0, if abort { 0 } else { 1 }]));
+    record.value = None;
+
+    record
+}
+
+#[cfg(test)]
+fn test_aborted_transaction(producer_id: i64, first_offset: i64) -> AbortedTransaction {
+    AbortedTransaction {
+        producer_id: ProducerId(producer_id),
+        first_offset,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+fn test_record_offsets(records: &[Record]) -> Vec<i64> {
+    records.iter().map(|record| record.offset).collect()
+}
+
+#[test]
+pub fn test_abort_marker_is_recognized_by_control_type() {
+    assert!(is_abort_marker(&test_control_record(0, 1, true)));
+    assert!(!is_abort_marker(&test_control_record(0, 1, false)));
+
+    let mut short_key = test_control_record(0, 1, true);
+    short_key.key = Some(Bytes::from(vec![0, 0]));
+    assert!(!is_abort_marker(&short_key));
+}
+
+#[test]
+pub fn test_read_committed_drops_aborted_range() {
+    let records = 
+        vec![
+            test_transactional_record(0, 1),
+            test_transactional_record(1, 1),
+            test_control_record(2, 1, true),
+            test_transactional_record(3, 2),
+            test_control_record(4, 2, false),
+        ];
+
+    let filtered = filter_records(records, &[test_aborted_transaction(1, 0)], IsolationLevel::ReadCommitted);
+
+    assert_eq!(test_record_offsets(&filtered), vec![3]);
+}
+
+#[test]
+pub fn test_read_committed_filters_each_transaction_of_producer() {
+    // producer 1 aborts its first transaction, commits the second and aborts the third
+    let records = 
+        vec![
+            test_transactional_record(0, 1),
+            test_control_record(1, 1, true),
+            test_transactional_record(2, 1),
+            test_control_record(3, 1, false),
+            test_transactional_record(4, 1),
+            test_transactional_record(5, 1),
+            test_control_record(6, 1, true),
+        ];
+
+    let aborted_transactions = [test_aborted_transaction(1, 4), test_aborted_transaction(1, 0)];
+    let filtered = filter_records(records, &aborted_transactions, IsolationLevel::ReadCommitted);
+
+    assert_eq!(test_record_offsets(&filtered), vec![2]);
+}
+
+#[test]
+pub fn test_commit_marker_keeps_records_of_other_producer_aborted() {
+    // commit marker of producer 2 must not end aborted transaction of producer 1
+    let records = 
+        vec![
+            test_transactional_record(0, 1),
+            test_transactional_record(1, 2),
+            test_control_record(2, 2, false),
+            test_transactional_record(3, 1),
+            test_control_record(4, 1, true),
+        ];
+
+    let filtered = filter_records(records, &[test_aborted_transaction(1, 0)], IsolationLevel::ReadCommitted);
+
+    assert_eq!(test_record_offsets(&filtered), vec![1]);
+}
+
+#[test]
+pub fn test_read_uncommitted_keeps_aborted_records_and_drops_markers() {
+    let records = 
+        vec![
+            test_transactional_record(0, 1),
+            test_control_record(1, 1, true),
+            test_transactional_record(2, 2),
+            test_control_record(3, 2, false),
+        ];
+
+    let filtered = filter_records(records, &[test_aborted_transaction(1, 0)], IsolationLevel::ReadUncommitted);
+
+    assert_eq!(test_record_offsets(&filtered), vec![0, 2]);
+}
+
+#[test]
+pub fn test_heartbeat_rebalance_error_drops_fetch_state() {
+    for error_code in [22, 25, 27] {
+        let mut state = test_fetch_state();
+        state.group_subscription.generation_id = 5;
+        state.group_subscription.member_id = String::from("member-1");
+
+        HeartbeatResponse { error_code, ..Default::default() }.process_response(&mut state).unwrap();
+
+        assert_eq!(state.group_subscription.generation_id, -1);
+        assert!(state.fetch_state.is_empty());
+        assert_eq!(state.group_subscription.member_id.is_empty(), error_code == 25);
+    }
+}
+
+#[test]
+pub fn test_heartbeat_thread_gives_up_membership_when_poll_interval_expires() {
+    let mut configuration = test_consumer_configuration();
+    if let Configuration::ConsumerConfiguration { max_poll_interval_ms, session_timeout_ms, .. } = &mut configuration {
+        *max_poll_interval_ms = 0;
+        *session_timeout_ms = 30;
+    }
+
+    let shared: SharedHeartbeat = Arc::new((Mutex::new(HeartbeatState::new()), Condvar::new()));
+    shared.0.lock().unwrap().group_subscription = 
+        Some(GroupSubscription { member_id: String::from("member-1"), generation_id: 5, ..Default::default() });
+
+    let heartbeat = Heartbeat::new(&configuration, HashMap::new(), shared.clone()).unwrap();
+    let heartbeat_thread = thread::spawn(move || heartbeat.run());
+
+    // failed LeaveGroup is left to session timeout, membership is given up regardless
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while shared.0.lock().unwrap().membership_loss.is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    {
+        let (lock, condvar) = &*shared;
+        let mut heartbeat_state = lock.lock().unwrap();
+        assert_eq!(heartbeat_state.membership_loss, Some(MembershipLoss::Removed));
+        assert!(heartbeat_state.group_subscription.is_none());
+
+        heartbeat_state.closed = true;
+        condvar.notify_all();
+    }
+
+    heartbeat_thread.join().unwrap();
+}
+
+#[test]
+pub fn test_consumed_offsets_point_after_last_returned_record() {
+    let mut state = test_fetch_state();
+    let partitions = state.fetch_state.get_mut("test_topic").unwrap();
+    // records up to offset 14 were returned by poll
+    partitions.get_mut(&0).unwrap().consumed_offset = 14;
+    // records up to offset 9 were returned and committed already
+    partitions.get_mut(&1).unwrap().consumed_offset = 9;
+
+    assert_eq!(state.uncommitted_offsets(), HashMap::from([(TopicPartition::new("test_topic", 0), 15)]));
+}
+
+#[test]
+pub fn test_offset_commit_rebalance_error_drops_uncommitted_offsets() {
+    for error_code in [22, 25, 27] {
+        let mut state = test_fetch_state();
+        state.group_subscription.generation_id = 5;
+        state.group_subscription.member_id = String::from("member-1");
+        state.fetch_state.get_mut("test_topic").unwrap().get_mut(&0).unwrap().consumed_offset = 14;
+        assert!(state.has_uncommitted_offsets());
+
+        let response = 
+            OffsetCommitResponse {
+                topics: vec![
+                    OffsetCommitResponseTopic {
+                        name: TopicName(to_kafka_str("test_topic")),
+                        partitions: vec![OffsetCommitResponsePartition { partition_index: 0, error_code, ..Default::default() }],
+                        ..Default::default()
+                    }
+                ],
+                ..Default::default()
+            };
+
+        response.process_response(&mut state).unwrap();
+
+        assert_eq!(state.group_subscription.generation_id, -1);
+        assert!(state.fetch_state.is_empty());
+        assert!(!state.has_uncommitted_offsets());
+        assert_eq!(state.group_subscription.member_id.is_empty(), error_code == 25);
+    }
+}
+
+// batch of given number of records for partition of test_topic, as drained from accumulator
+#[cfg(test)]
+fn test_producer_batch(partition: i32, record_count: usize) -> (ProducerBatch, Vec<SendHandle>) {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 120000);
+
+    let handles = 
+        (0..record_count)
+            .map(|_| {
+                let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+                record.set_partition(partition);
+                accumulator.append(record)
+            })
+            .collect();
+
+    (accumulator.drain_ready(Instant::now()).remove(0), handles)
+}
+
+#[cfg(test)]
+fn test_produce_response(partition_responses: Vec<PartitionProduceResponse>) -> ProduceResponse {
+    ProduceResponse {
+        responses: IndexMap::from([(
+            TopicName(to_kafka_str("test_topic")),
+            TopicProduceResponse {
+                partition_responses,
+                ..Default::default()
+            }
+        )]),
+        ..Default::default()
+    }
+}
+
+#[test]
+pub fn test_produce_response_completes_retries_and_fails_batches() {
+    let mut state = CallState::new(&test_producer_configuration()).unwrap();
+    let (completed, completed_handles) = test_producer_batch(0, 2);
+    let (retried, retried_handles) = test_producer_batch(1, 1);
+    let (duplicate, duplicate_handles) = test_producer_batch(2, 1);
+    let (mut failed, failed_handles) = test_producer_batch(3, 1);
+    let (unanswered, _) = test_producer_batch(4, 1);
+    failed.base_sequence = 5;
+    state.batches_to_send = vec![completed, retried, duplicate, failed, unanswered];
+
+    test_produce_response(vec![
+        PartitionProduceResponse { index: 0, error_code: 0, base_offset: 100, log_append_time_ms: -1, ..Default::default() },
+        // NOT_LEADER_OR_FOLLOWER
+        PartitionProduceResponse { index: 1, error_code: 6, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+        // DUPLICATE_SEQUENCE_NUMBER
+        PartitionProduceResponse { index: 2, error_code: 46, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+        // CORRUPT_MESSAGE
+        PartitionProduceResponse { index: 3, error_code: 2, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+    ]).process_response(&mut state).unwrap();
+
+    let offsets: Vec<i64> = completed_handles.into_iter().map(|handle| handle.wait().unwrap().offset).collect();
+    assert_eq!(offsets, vec![100, 101]);
+
+    // retriable error refreshes metadata and leaves the batch for retry, its records are not delivered yet
+    assert_eq!(state.batches_to_retry.len(), 1);
+    assert_eq!(state.batches_to_retry[0].0.topic_partition.partition, 1);
+    assert!(state.metadata_refresh_needed);
+    assert!(retried_handles[0].try_result().is_none());
+
+    // duplicate was written by earlier attempt, its offset is not known
+    assert_eq!(duplicate_handles.into_iter().next().unwrap().wait().unwrap().offset, -1);
+
+    // fatal error fails the records, transaction and sequence of the partition
+    assert!(failed_handles.into_iter().next().unwrap().wait().unwrap_err().0.contains("error code 2"));
+    assert!(state.transaction_error.is_some());
+    assert!(state.producer_epoch_bump_needed);
+
+    // batch without response is left for the caller
+    assert_eq!(state.batches_to_send.len(), 1);
+    assert_eq!(state.batches_to_send[0].topic_partition.partition, 4);
+}
+
+#[test]
+pub fn test_produce_response_splits_too_large_batch() {
+    let mut state = CallState::new(&test_producer_configuration()).unwrap();
+    let (batch, _) = test_producer_batch(0, 2);
+    let (single_record_batch, single_record_handles) = test_producer_batch(1, 1);
+    state.batches_to_send = vec![batch, single_record_batch];
+
+    // MESSAGE_TOO_LARGE
+    test_produce_response(vec![
+        PartitionProduceResponse { index: 0, error_code: 10, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+        PartitionProduceResponse { index: 1, error_code: 10, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+    ]).process_response(&mut state).unwrap();
+
+    assert_eq!(state.batches_to_split.len(), 1);
+    assert_eq!(state.batches_to_split[0].records.len(), 2);
+
+    // single record cannot be split any further
+    let error = single_record_handles.into_iter().next().unwrap().wait().unwrap_err();
+    assert!(error.0.contains("larger than broker accepts"));
+    assert!(state.transaction_error.is_some());
+    // batch without sequence did not move sequence of the partition
+    assert!(!state.producer_epoch_bump_needed);
+}
+
+#[test]
+pub fn test_produce_response_maps_record_errors() {
+    let mut state = CallState::new(&test_producer_configuration()).unwrap();
+    let (batch, handles) = test_producer_batch(0, 2);
+    state.batches_to_send = vec![batch];
+
+    // INVALID_RECORD
+    test_produce_response(vec![
+        PartitionProduceResponse { 
+            index: 0, 
+            error_code: 87, 
+            base_offset: -1, 
+            log_append_time_ms: -1, 
+            error_message: Some(to_kafka_str("compacted topic requires key")),
+            record_errors: vec![
+                BatchIndexAndErrorMessage { batch_index: 1, batch_index_error_message: Some(to_kafka_str("null key")), ..Default::default() },
+            ],
+            ..Default::default() 
+        },
+    ]).process_response(&mut state).unwrap();
+
+    let errors: Vec<String> = handles.into_iter().map(|handle| handle.wait().unwrap_err().0).collect();
+    assert!(errors[0].contains("had invalid records: compacted topic requires key"));
+    assert_eq!(errors[1], "Record was rejected by broker: null key");
+    assert!(state.transaction_error.is_some());
+}
+
+#[test]
+pub fn test_transaction_with_failed_record_is_aborted_before_epoch_bump() {
+    let mut configuration = test_producer_configuration();
+
+    if let Configuration::ProducerConfiguration { transactional_id, .. } = &mut configuration {
+        *transactional_id = Some(String::from("test-transaction"));
+    }
+
+    let mut state = CallState::new(&configuration).unwrap();
+    state.producer_id = 7;
+    state.producer_epoch = 3;
+    state.transaction_partitions.insert((String::from("test_topic"), 0));
+
+    let (mut batch, _) = test_producer_batch(0, 1);
+    batch.base_sequence = 0;
+    state.batches_to_send = vec![batch];
+
+    // CORRUPT_MESSAGE
+    test_produce_response(vec![
+        PartitionProduceResponse { index: 0, error_code: 2, base_offset: -1, log_append_time_ms: -1, ..Default::default() },
+    ]).process_response(&mut state).unwrap();
+
+    // sequence is lost, but epoch is not bumped while the transaction is in progress
+    assert!(state.producer_epoch_bump_needed);
+    assert!(state.in_transaction());
+    assert!(!state.producer_epoch_bump_allowed());
+    assert!(state.check_transaction_committable().is_err());
+
+    // bumped epoch keeps the failed transaction, so its commit is still refused
+    InitProducerIdResponse { producer_id: ProducerId(7), producer_epoch: 4, ..Default::default() }.process_response(&mut state).unwrap();
+    assert!(state.transaction_error.is_some());
+    assert!(state.in_transaction());
+    assert!(state.check_transaction_committable().is_err());
+
+    // sequences of the failed transaction are still lost, its records are rejected until it is aborted
+    state.producer_epoch_bump_needed = true;
+
+    // abort ends the transaction, only then the epoch can be bumped
+    EndTxnResponse::default().process_response(&mut state).unwrap();
+    assert!(state.transaction_error.is_none());
+    assert!(!state.in_transaction());
+    assert!(state.producer_epoch_bump_allowed());
+    assert!(state.check_transaction_committable().is_ok());
+
+    InitProducerIdResponse { producer_id: ProducerId(7), producer_epoch: 5, ..Default::default() }.process_response(&mut state).unwrap();
+    assert_eq!(state.producer_id, 7);
+    assert_eq!(state.producer_epoch, 5);
+    assert!(!state.producer_epoch_bump_needed);
+}
+
+#[test]
+pub fn test_unacknowledged_batch_completes_without_offset() {
+    // with acks=0 broker does not answer, records are completed once written to connection
+    let (batch, handles) = test_producer_batch(0, 2);
+    let timestamps = batch.timestamps.clone();
+    batch.complete_without_offset();
+
+    for (handle, timestamp) in handles.into_iter().zip(timestamps) {
+        let record_metadata = handle.wait().unwrap();
+        assert_eq!((record_metadata.partition, record_metadata.offset), (0, -1));
+        assert_eq!(record_metadata.timestamp, timestamp);
+    }
+}
+
+#[test]
+pub fn test_in_flight_batch_fails_when_delivery_timeout_expires() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 100);
+
+    let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    record.set_partition(0);
+    let handle = accumulator.append(record);
+
+    let mut in_flight = accumulator.drain_ready(Instant::now());
+    accumulator.in_flight_batches += in_flight.len();
+    in_flight[0].base_sequence = 0;
+
+    // batch was not answered before delivery timeout expired, it is not retried anymore
+    let answered_at = Instant::now() + Duration::from_millis(200);
+    let error = accumulator.reenqueue(in_flight.remove(0), KafkaCallerError::new("No response for partition 0 of topic 'test_topic'"), answered_at);
+
+    let error = error.unwrap();
+    assert!(error.0.contains("Delivery timeout of 100 ms expired"));
+    assert!(error.0.contains("last error: No response"));
+    assert_eq!(handle.wait().unwrap_err().0, error.0);
+    assert!(accumulator.sequence_lost);
+    assert!(accumulator.is_empty());
+}
+
+#[test]
+pub fn test_producer_can_be_moved_to_other_thread() {
+    fn assert_send<T: Send>() {}
+
+    assert_send::<Producer>();
+    assert_send::<Producer<String, String>>();
+}
+
+#[test]
+pub fn test_record_accumulator_reports_lost_sequence_of_expired_batch() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 100);
+
+    for partition in 0..2 {
+        let mut record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+        record.set_partition(partition);
+        accumulator.append(record);
+    }
+
+    let mut ready = accumulator.drain_ready(Instant::now());
+    assert_eq!(ready.len(), 2);
+    let error = KafkaCallerError::new("timeout");
+    let expired_at = Instant::now() + Duration::from_millis(200);
+
+    // batch which was never sent does not hold a sequence
+    assert!(accumulator.reenqueue(ready.remove(0), error.clone(), expired_at).is_some());
+    assert!(!accumulator.sequence_lost);
+
+    let mut sequenced = ready.remove(0);
+    sequenced.base_sequence = 5;
+    assert!(accumulator.reenqueue(sequenced, error.clone(), Instant::now()).is_none());
+    assert!(accumulator.fail_expired(expired_at).is_some());
+    assert!(accumulator.sequence_lost);
+    assert!(accumulator.is_empty());
+}
+
+#[test]
+pub fn test_record_accumulator_reports_lost_sequence_of_aborted_batch() {
+    let mut accumulator = RecordAccumulator::new(16384, 0, 33554432, 100);
+    let error = KafkaCallerError::new("transaction aborted");
+
+    // batch which was never sent does not hold a sequence
+    accumulator.append(PutRecord::new_with_key_value_str("test_topic", "key", "value"));
+    assert_eq!(accumulator.abort_batches(error.clone()), 1);
+    assert!(!accumulator.sequence_lost);
+
+    accumulator.append(PutRecord::new_with_key_value_str("test_topic", "key", "value"));
+    let mut sequenced = accumulator.drain_ready(Instant::now()).remove(0);
+    sequenced.base_sequence = 5;
+    assert!(accumulator.reenqueue(sequenced, error.clone(), Instant::now()).is_none());
+    assert_eq!(accumulator.abort_batches(error), 1);
+    assert!(accumulator.sequence_lost);
+    assert!(accumulator.is_empty());
+}
+
+#[cfg(test)]
+fn test_find_coordinator_response(key: &str, node_id: i32) -> FindCoordinatorResponse {
+    FindCoordinatorResponse {
+        coordinators: vec![
+            ResponseCoordinator {
+                key: to_kafka_str(key),
+                node_id: BrokerId(node_id),
+                host: to_kafka_str("localhost"),
+                port: 9092,
+                ..Default::default()
+            }
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+pub fn test_coordinators_with_same_key_are_kept_apart_by_key_type() {
+    let mut state = CallState::new(&test_consumer_configuration()).unwrap();
+    let key = String::from("test-client-rs.group");
+
+    // group coordinator of configured group
+    test_find_coordinator_response(&key, 1).process_response(&mut state).unwrap();
+
+    // transaction coordinator of transactional id equal to the group id
+    state.coordinator_key = Some((key.clone(), TRANSACTION_COORDINATOR));
+    test_find_coordinator_response(&key, 2).process_response(&mut state).unwrap();
+
+    assert_eq!(state.coordinators.len(), 2);
+    assert_eq!(state.coordinators[&(GROUP_COORDINATOR, key.clone())].node_id, 1);
+    assert_eq!(state.coordinators[&(TRANSACTION_COORDINATOR, key)].node_id, 2);
+}
+
+#[test]
+pub fn test_repeated_header_keys_survive_produce_and_consume() {
+    let mut put_record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    put_record.add_header_str("trace", "first");
+    put_record.add_header_bytes("span", &[1, 2]);
+    put_record.add_header_str("trace", "second");
+    put_record.headers.add("trace", None);
+
+    for compression_type in [CompressionType::None, CompressionType::Gzip, CompressionType::Snappy, CompressionType::Lz4, CompressionType::Zstd] {
+        let mut record: Record = (&put_record).into();
+        record.offset = 0;
+        record.timestamp = 1700000000000;
+
+        let encode_options = 
+            RecordEncodeOptions {
+                compression: compression_type.into(),
+                version: 2,
+            };
+
+        let mut record_bytes = BytesMut::default();
+        RecordBatchEncoder::encode(&mut record_bytes, [record].iter(), &encode_options).unwrap();
+
+        let consumer_records: Vec<ConsumerRecord> = 
+            RecordBatchDecoder::decode(&mut record_bytes.freeze())
+                .unwrap()
+                .into_iter()
+                .map(|record| ConsumerRecord::from_record("test_topic", 0, record))
+                .collect();
+
+        assert_eq!(consumer_records.len(), 1, "{:?}", compression_type);
+        assert_eq!(consumer_records[0].headers, put_record.headers, "{:?}", compression_type);
+        assert_eq!(
+            consumer_records[0].headers.headers("trace").map(|header| header.value_str()).collect::<Vec<Option<&str>>>(), 
+            vec![Some("first"), Some("second"), None]
+        );
+    }
+}
+
+#[test]
+pub fn test_record_batch_fields_survive_encoding() {
+    let records: Vec<Record> = 
+        (0..3)
+            .map(|index| {
+                let mut record: Record = (&PutRecord::new_with_key_value_str("test_topic", "key", "value")).into();
+                record.offset = 100 + index;
+                record.timestamp = 1700000000000 + index;
+                record.producer_id = 7;
+                record.producer_epoch = 2;
+                record.sequence = 40 + index as i32;
+                record.transactional = true;
+                record.partition_leader_epoch = 3;
+                record
+            })
+            .collect();
+
+    let encode_options = 
+        RecordEncodeOptions {
+            compression: CompressionType::None.into(),
+            version: 2,
+        };
+
+    // two batches followed by a partial one, which is left for the next fetch
+    let mut record_bytes = BytesMut::default();
+    RecordBatchEncoder::encode(&mut record_bytes, records[..2].iter(), &encode_options).unwrap();
+    RecordBatchEncoder::encode(&mut record_bytes, records[2..].iter(), &encode_options).unwrap();
+    let partial_batch_length = record_bytes.len() - 5;
+    let mut fetched_bytes = record_bytes.freeze();
+
+    assert_eq!(RecordBatchDecoder::decode(&mut fetched_bytes.clone()).unwrap(), records);
+    assert_eq!(RecordBatchDecoder::decode(&mut fetched_bytes.split_to(partial_batch_length)).unwrap(), records[..2].to_vec());
+}
+
+#[test]
+pub fn test_record_batch_with_corrupted_crc_is_rejected() {
+    let mut record: Record = (&PutRecord::new_with_key_value_str("test_topic", "key", "value")).into();
+    record.offset = 0;
+
+    let encode_options = 
+        RecordEncodeOptions {
+            compression: CompressionType::None.into(),
+            version: 2,
+        };
+
+    let mut record_bytes = BytesMut::default();
+    RecordBatchEncoder::encode(&mut record_bytes, [record].iter(), &encode_options).unwrap();
+    let last_byte = record_bytes.len() - 1;
+    record_bytes[last_byte] ^= 0xFF;
+
+    assert!(RecordBatchDecoder::decode(&mut record_bytes.freeze()).is_err());
+}
+
+#[test]
+pub fn test_legacy_message_set_is_rejected_with_clear_error() {
+    // message of magic 1: offset, size, crc, magic, attributes, timestamp, null key, value "value"
+    let mut message_set = BytesMut::default();
+    message_set.put_i64(42);
+    message_set.put_i32(4 + 1 + 1 + 8 + 4 + 4 + 5);
+    message_set.put_u32(0);
+    message_set.put_i8(1);
+    message_set.put_i8(0);
+    message_set.put_i64(1700000000000);
+    message_set.put_i32(-1);
+    message_set.put_i32(5);
+    message_set.put_slice(b"value");
+
+    let error = RecordBatchDecoder::decode(&mut message_set.freeze()).unwrap_err();
+    assert!(error.to_string().contains("Legacy message set with magic '1' at offset '42'"));
+}
+
+#[test]
+pub fn test_record_size_estimate_covers_encoded_record() {
+    let mut put_record = PutRecord::new_with_key_value_str("test_topic", "key", "value");
+    for index in 0..20 {
+        put_record.add_header_str(&format!("header-{}", index), "header value");
+    }
+    put_record.headers.add("null-header", None);
+
+    let mut record: Record = (&put_record).into();
+    record.offset = 0;
+    record.timestamp = 1700000000000;
+
+    let encode_options = 
+        RecordEncodeOptions {
+            compression: CompressionType::None.into(),
+            version: 2,
+        };
+
+    let mut record_bytes = BytesMut::default();
+    RecordBatchEncoder::encode(&mut record_bytes, [record].iter(), &encode_options).unwrap();
+
+    // header key and value lengths are counted, so the estimate is exceeded only by overhead of the record itself
+    let estimate = put_record.size_in_bytes();
+    assert!(record_bytes.len() <= estimate + MAX_RECORD_OVERHEAD + RECORD_BATCH_OVERHEAD);
+    assert!(record_bytes.len() > estimate + RECORD_BATCH_OVERHEAD);
+}